@@ -0,0 +1,29 @@
+// Bakes build details into the binary for `--version`, so bug reports can be matched to exact sources
+use std::{env, process::Command, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+fn main() {
+    // Nix builds have no .git, so the commit can also be passed in
+    println!("cargo:rerun-if-env-changed=PATCHSHEBANGS_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let commit = env::var("PATCHSHEBANGS_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_time = match env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok()) {
+        Some(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        None => SystemTime::now(),
+    };
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=PATCHSHEBANGS_GIT_COMMIT={}", commit.as_deref().unwrap_or("unknown"));
+    println!("cargo:rustc-env=PATCHSHEBANGS_BUILD_DATE={}", &humantime::format_rfc3339_seconds(build_time).to_string()[..10]);
+    println!("cargo:rustc-env=PATCHSHEBANGS_FEATURES={}", if features.is_empty() { "none".to_string() } else { features.join(",") });
+    println!("cargo:rustc-env=PATCHSHEBANGS_TARGET={}", env::var("TARGET").unwrap());
+}
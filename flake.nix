@@ -73,6 +73,8 @@
                             cp -r ./target/release "$out/bin/"
                         '';
                         XDG_CACHE_HOME = "/tmp/build/cache";
+                        # the source copy has no .git; shown by --version
+                        PATCHSHEBANGS_GIT_COMMIT = self.shortRev or self.dirtyShortRev or "unknown";
                     };
                     
                     devShells = xome.simpleMakeHomeFor {
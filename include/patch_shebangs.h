@@ -0,0 +1,46 @@
+/* The C API of the patch_shebangs library (cargo feature "capi"). Link against
+ * libpatch_shebangs.so (or .dylib / .dll) from
+ * `cargo rustc --lib --release --no-default-features --features capi --crate-type cdylib`,
+ * which ends up in target/release. */
+#ifndef PATCH_SHEBANGS_H
+#define PATCH_SHEBANGS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* All zeroes is the default: interpreters from $PATH, no update, one file at a time */
+typedef struct ps_options {
+    /* colon-separated directories to find interpreters in; NULL for $PATH */
+    const char *search_path;
+    /* also re-patch shebangs that already point into /nix/store */
+    bool update;
+    /* files processed at once; 0 and 1 both mean one by one */
+    size_t jobs;
+} ps_options;
+
+typedef struct ps_report {
+    size_t patched;
+    size_t skipped;
+    size_t errors;
+    size_t denied;
+} ps_report;
+
+/* Patches every executable script under path. options and report may be NULL.
+ * Returns the exit code the patchShebangsRust CLI would: 0 success, 1 failed,
+ * 2 invalid arguments, 4 interpreters not found, 5 not idempotent. A panic
+ * inside the library returns 1, with its message in ps_last_error. */
+int ps_patch_tree(const char *path, const ps_options *options, ps_report *report);
+
+/* Why the last ps_patch_tree call on this thread failed (one line per failed
+ * file), or NULL. Valid until the next call. */
+const char *ps_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
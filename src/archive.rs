@@ -0,0 +1,437 @@
+//! Patching the scripts inside tar archives (optionally gzip, xz or zstd compressed) and
+//! zip files / Python wheels without unpacking them: members are copied into a new archive
+//! that differs only in the patched scripts
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use crate::{ContentHashes, Options, PatchError, PatchEvent, PatchReport, PatchedFile, Resolver, Result, SkipCode, SkippedFile, dispatch, nixshell::{self, Continuation}, process::{LinePlan, Outcome, plan_line, copy_permissions, new_mtime, sha256_hex, sync_parent}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Whether `path` is named like an archive `patch_archive` can handle
+pub fn is_archive(path: &Path) -> bool {
+    tar_compression(path).is_some() || is_zip(path)
+}
+
+/// `.zip` and `.whl` (Python wheels, which are zip files)
+pub fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "zip" || extension == "whl")
+}
+
+/// Patches a tar or zip archive, depending on its name
+pub fn patch_archive<R: Resolver + ?Sized>(path: &Path, options: &Options, resolver: &R) -> Result<PatchReport> {
+    if is_zip(path) { patch_zip(path, options, resolver) } else { patch_tar(path, options, resolver) }
+}
+
+/// Recognizes tar archives by their file name
+pub fn tar_compression(path: &Path) -> Option<Compression> {
+    let name = path.file_name()?.to_str()?;
+    [
+        (".tar", Compression::None),
+        (".tar.gz", Compression::Gzip),
+        (".tgz", Compression::Gzip),
+        (".tar.xz", Compression::Xz),
+        (".txz", Compression::Xz),
+        (".tar.zst", Compression::Zstd),
+        (".tzst", Compression::Zstd),
+    ]
+    .into_iter()
+    .find(|(suffix, _)| name.ends_with(suffix))
+    .map(|(_, compression)| compression)
+}
+
+/// Patches every executable script inside the archive at `path`, replacing the archive.
+/// Members are reported as `path/member`. Entry order and all header fields except the size
+/// are kept, and compression adds no timestamps, so the same input always repacks the same way.
+/// Like `patch_tree`, problems with single members are collected in the report.
+/// `Options::wrap_instead` doesn't apply here; members are always patched in place.
+pub fn patch_tar<R: Resolver + ?Sized>(path: &Path, options: &Options, resolver: &R) -> Result<PatchReport> {
+    let compression = tar_compression(path).ok_or_else(|| {
+        PatchError::from(io::Error::new(io::ErrorKind::InvalidInput, "not a .tar, .tar.gz, .tar.xz or .tar.zst file")).in_file(path, None)
+    })?;
+    replace_archive(path, options, |temp_path, handle| repack(path, temp_path, compression, options, resolver, handle))
+}
+
+/// Runs `repack` into a temp file next to `path`, which replaces `path` only if repack reports a change
+fn replace_archive(
+    path: &Path,
+    options: &Options,
+    repack: impl FnOnce(&Path, &mut dyn FnMut(PatchEvent)) -> Result<bool>,
+) -> Result<PatchReport> {
+    let observer = options.observer();
+    let mut report = PatchReport::default();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.patchShebangs-tmp", file_name));
+    let result = repack(&temp_path, &mut |event| dispatch(event, observer, &mut report));
+    let result = result.and_then(|changed| {
+        if changed {
+            let metadata = fs::metadata(path)?;
+            copy_permissions(&File::open(&temp_path)?, &metadata)?;
+            if let Some(mtime) = new_mtime(&metadata, options) {
+                fs::OpenOptions::new().write(true).open(&temp_path)?.set_modified(mtime)?;
+            }
+            if options.fsync {
+                fs::OpenOptions::new().write(true).open(&temp_path)?.sync_all()?;
+            }
+            fs::rename(&temp_path, path)?;
+            if options.fsync {
+                sync_parent(path)?;
+            }
+        }
+        Ok(())
+    });
+    let _ = fs::remove_file(&temp_path);
+    if let Err(error) = result {
+        let error = error.in_file(path, None);
+        observer.file_errored(path, &error);
+        return Err(error);
+    }
+    Ok(report)
+}
+
+/// Returns whether any member changed (if not, the original archive is left alone)
+fn repack<R: Resolver + ?Sized>(
+    path: &Path,
+    temp_path: &Path,
+    compression: Compression,
+    options: &Options,
+    resolver: &R,
+    handle: &mut dyn FnMut(PatchEvent),
+) -> Result<bool> {
+    let input = decoder(BufReader::new(File::open(path)?), compression)?;
+    let output = Encoder::new(BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).open(temp_path)?), compression)?;
+    let (changed, output) = repack_tar(input, output, path, options, resolver, handle)?;
+    output.finish()?.flush()?;
+    Ok(changed)
+}
+
+pub(crate) fn decoder<'a>(input: impl BufRead + 'a, compression: Compression) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(input)?),
+    })
+}
+
+/// Copies an uncompressed tar stream, patching the scripts in it (reported as `root/member`).
+/// Returns whether any member changed, and the output for the caller to finish
+pub(crate) fn repack_tar<W: Write, R: Resolver + ?Sized>(
+    input: impl Read,
+    output: W,
+    root: &Path,
+    options: &Options,
+    resolver: &R,
+    handle: &mut dyn FnMut(PatchEvent),
+) -> Result<(bool, W)> {
+    let mut archive = tar::Archive::new(input);
+    let mut builder = tar::Builder::new(output);
+    let mut changed = false;
+    // raw, so GNU long name and PAX entries are copied through as they are;
+    // they only get looked at for the name of the member that follows them
+    let mut long_name: Option<Vec<u8>> = None;
+    for entry in archive.entries()?.raw(true) {
+        let mut entry = entry?;
+        let mut header = entry.header().clone();
+        let entry_type = header.entry_type();
+        if entry_type.is_gnu_longname() || entry_type.is_pax_local_extensions() {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            long_name = if entry_type.is_gnu_longname() {
+                Some(data.split(|&b| b == 0).next().unwrap_or_default().to_vec())
+            } else {
+                tar::PaxExtensions::new(&data).flatten().find(|ext| ext.key() == Ok("path")).map(|ext| ext.value_bytes().to_vec())
+            };
+            builder.append(&header, data.as_slice())?;
+            continue;
+        }
+        let is_file = entry_type.is_file() || entry_type == tar::EntryType::Continuous;
+        // a long link name belongs to the same member as the long name before it
+        let name = if entry_type.is_gnu_longlink() { None } else { long_name.take() };
+        let executable = header.mode().is_ok_and(|mode| mode & 0o100 != 0);
+
+        // only scripts are read into memory, everything else (binaries, ...) is streamed through
+        let mut magic = Vec::with_capacity(2);
+        if is_file && executable {
+            (&mut entry).take(2).read_to_end(&mut magic)?;
+        }
+        if magic != b"#!" {
+            builder.append(&header, magic.as_slice().chain(entry))?;
+            continue;
+        }
+        let mut data = magic;
+        entry.read_to_end(&mut data)?;
+        let name = name.unwrap_or_else(|| header.path_bytes().into_owned());
+        let member = root.join(String::from_utf8_lossy(&name).trim_start_matches("./"));
+        if let Some(patched) = patch_member(&member, &data, options, resolver, handle)? {
+            data = patched;
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            changed = true;
+        }
+        builder.append(&header, data.as_slice())?;
+    }
+    Ok((changed, builder.into_inner()?))
+}
+
+/// The compressors' own finishing on drop swallows errors, which would let a truncated archive replace the original
+pub(crate) enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Compresses without embedding timestamps or file names, so the output only depends on the input
+    pub(crate) fn new(output: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(output),
+            Compression::Gzip => Self::Gzip(flate2::GzBuilder::new().write(output, flate2::Compression::default())),
+            Compression::Xz => Self::Xz(xz2::write::XzEncoder::new(output, 6)),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(output, 0)?),
+        })
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(output) => Ok(output),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(output) => output.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(output) => output.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Runs one member through the same rules as a file on disk, returning its new content if it changed
+fn patch_member<R: Resolver + ?Sized>(
+    member: &Path,
+    data: &[u8],
+    options: &Options,
+    resolver: &R,
+    handle: &mut dyn FnMut(PatchEvent),
+) -> Result<Option<Vec<u8>>> {
+    handle(PatchEvent::Started(member.to_path_buf()));
+    let first_line = data.split_inclusive(|&b| b == b'\n').next().unwrap_or_default().to_vec();
+    let continuation = if nixshell::wanted(&first_line, options) { nixshell::continuation_of(&data[first_line.len()..]) } else { &[] };
+    let skipped = |code| PatchEvent::Skipped(SkippedFile::new(member, code));
+    let planned = plan_line(member, first_line, continuation, options, resolver).and_then(|plan| match plan {
+        LinePlan::Done(outcome) => Ok((outcome, None)),
+        LinePlan::Rewrite { original, new, skip_reason, continuation } => {
+            let content = std::str::from_utf8(data).map_err(|_| PatchError::NonUtf8 { path: member.to_path_buf() })?;
+            let rewritten = rewrite_content(content, &original, &new, skip_reason, continuation.as_ref(), options, resolver).map_err(|e| e.in_file(member, None))?;
+            Ok(match rewritten {
+                None => (Outcome::Skipped(skip_reason.unwrap_or(SkipCode::UpToDate)), None),
+                Some(rewritten) => {
+                    let header_len = continuation.as_ref().map_or(0, |continuation| continuation.original.len());
+                    let hashes = options.hash_contents.then(|| content_hashes(content, &rewritten.content, header_len));
+                    let outcome = Outcome::Patched { old: original, new: rewritten.shebang, hashes, tokens_replaced: rewritten.tokens_replaced, wrapped: None };
+                    (outcome, Some(rewritten.content.into_bytes()))
+                }
+            })
+        }
+    });
+    match planned {
+        Ok((Outcome::Patched { old, new, hashes, tokens_replaced, wrapped }, content)) => {
+            handle(PatchEvent::Patched(PatchedFile { path: member.to_path_buf(), old_shebang: old, new_shebang: new, hashes, tokens_replaced, wrapped }));
+            Ok(content)
+        }
+        Ok((Outcome::Skipped(code), _)) => {
+            handle(skipped(code));
+            Ok(None)
+        }
+        Ok((Outcome::Malformed(problem), _)) => {
+            handle(PatchEvent::Warning { path: member.to_path_buf(), message: format!("{}, skipping", problem) });
+            handle(PatchEvent::Skipped(SkippedFile { path: member.to_path_buf(), code: SkipCode::Malformed, reason: problem }));
+            Ok(None)
+        }
+        // members are written into the new archive, never in place
+        Ok((Outcome::ReadOnly { .. }, _)) => unreachable!("plan_line never reports a read-only filesystem"),
+        Err(error) => {
+            handle(PatchEvent::Error(error.in_file(member, None)));
+            Ok(None)
+        }
+    }
+}
+
+/// Patches the scripts inside a zip file or wheel: executable members, and in wheels everything under
+/// `*.data/scripts/`, whose `#!python` launcher stubs installers normally rewrite. Unchanged members are
+/// copied as they are (compressed data and all), patched ones keep their name, timestamp, permissions and
+/// compression method, and a wheel's `RECORD` gets the new hashes and sizes so installers still accept it.
+pub fn patch_zip<R: Resolver + ?Sized>(path: &Path, options: &Options, resolver: &R) -> Result<PatchReport> {
+    replace_archive(path, options, |temp_path, handle| repack_zip(path, temp_path, options, resolver, handle))
+}
+
+fn repack_zip<R: Resolver + ?Sized>(
+    path: &Path,
+    temp_path: &Path,
+    options: &Options,
+    resolver: &R,
+    handle: &mut dyn FnMut(PatchEvent),
+) -> Result<bool> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?)).map_err(zip_error)?;
+
+    let mut patched: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut record = None;
+    for index in 0..archive.len() {
+        let mut member = archive.by_index(index).map_err(zip_error)?;
+        let name = member.name().to_string();
+        if name.ends_with(".dist-info/RECORD") {
+            record = Some(name.clone());
+        }
+        let executable = member.unix_mode().is_some_and(|mode| mode & 0o100 != 0);
+        if !member.is_file() || !(executable || name.contains(".data/scripts/")) {
+            continue;
+        }
+        let mut data = Vec::new();
+        member.read_to_end(&mut data)?;
+        if data.starts_with(b"#!")
+            && let Some(new_data) = patch_member(&path.join(&name), &data, options, resolver, handle)?
+        {
+            patched.insert(name, new_data);
+        }
+    }
+    if patched.is_empty() {
+        return Ok(false);
+    }
+    if let Some(record) = &record {
+        let mut text = String::new();
+        archive.by_name(record).map_err(zip_error)?.read_to_string(&mut text)?;
+        let updated = update_record(&text, &patched);
+        patched.insert(record.clone(), updated.into_bytes());
+    }
+
+    let mut writer = zip::ZipWriter::new(BufWriter::new(fs::OpenOptions::new().write(true).create_new(true).open(temp_path)?));
+    writer.set_raw_comment(archive.comment().into());
+    for index in 0..archive.len() {
+        let member = archive.by_index_raw(index).map_err(zip_error)?;
+        let Some(data) = patched.get(member.name()) else {
+            writer.raw_copy_file(member).map_err(zip_error)?;
+            continue;
+        };
+        let mut file_options = zip::write::SimpleFileOptions::default()
+            .compression_method(member.compression())
+            .last_modified_time(member.last_modified().unwrap_or_default());
+        if let Some(mode) = member.unix_mode() {
+            file_options = file_options.unix_permissions(mode);
+        }
+        let name = member.name().to_string();
+        drop(member);
+        writer.start_file(name, file_options).map_err(zip_error)?;
+        writer.write_all(data)?;
+    }
+    writer.finish().map_err(zip_error)?.flush()?;
+    Ok(true)
+}
+
+/// Updates the `path,sha256=<urlsafe base64>,size` lines of the members that changed
+fn update_record(record: &str, patched: &HashMap<String, Vec<u8>>) -> String {
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+    record
+        .lines()
+        .map(|line| {
+            let member = line.rsplitn(3, ',').nth(2).unwrap_or(line);
+            match patched.get(member) {
+                Some(data) => {
+                    let hash = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(data));
+                    format!("{},sha256={},{}\n", member, hash, data.len())
+                }
+                None => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+fn zip_error(error: zip::result::ZipError) -> PatchError {
+    match error {
+        zip::result::ZipError::Io(error) => error.into(),
+        error => io::Error::new(io::ErrorKind::InvalidData, error).into(),
+    }
+}
+
+struct Rewritten {
+    content: String,
+    shebang: String,
+    tokens_replaced: usize,
+}
+
+/// Applies a planned rewrite plus `Options::tokens` to a whole script. `None` when nothing changes.
+/// Errors are put in the member's context by the caller
+fn rewrite_content<R: Resolver + ?Sized>(
+    content: &str,
+    original_shebang: &str,
+    new_interpreter_line: &str,
+    skip_reason: Option<SkipCode>,
+    continuation: Option<&Continuation>,
+    options: &Options,
+    resolver: &R,
+) -> Result<Option<Rewritten>> {
+    let shebang = if skip_reason.is_some() { original_shebang } else { new_interpreter_line };
+    let mut updated = content.replacen(original_shebang, shebang, 1);
+    if let Some(continuation) = continuation {
+        // the nix-shell lines directly follow the first line
+        let start = updated.find('\n').map_or(updated.len(), |end| end + 1);
+        updated.replace_range(start..start + continuation.original.len(), &continuation.new);
+    }
+    let tokens_replaced = replace_tokens(&mut updated, &options.tokens, resolver)?;
+    if tokens_replaced == 0 && skip_reason.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(Rewritten { content: updated, shebang: shebang.to_string(), tokens_replaced }))
+}
+
+/// `header_len` are the bytes after the first line that belong to the shebang (its nix-shell lines)
+fn content_hashes(before: &str, after: &str, header_len: usize) -> ContentHashes {
+    let body = before.split_once('\n').map(|(_, body)| &body[header_len..]).unwrap_or("");
+    ContentHashes {
+        before: sha256_hex(before.as_bytes()),
+        after: sha256_hex(after.as_bytes()),
+        body: sha256_hex(body.as_bytes()),
+    }
+}
+
+/// Replaces every occurrence of each token with the resolved path of its program, returning how many were replaced
+fn replace_tokens<R: Resolver + ?Sized>(content: &mut String, tokens: &[(String, String)], resolver: &R) -> Result<usize> {
+    let mut replaced = 0;
+    // an empty token would match between every two bytes
+    for (token, program) in tokens.iter().filter(|(token, _)| !token.is_empty()) {
+        let count = content.matches(token.as_str()).count();
+        if count == 0 {
+            continue;
+        }
+        let program_path = if Path::new(program).is_absolute() { program.clone() } else { resolver.resolve(program)? };
+        *content = content.replace(token.as_str(), &program_path);
+        replaced += count;
+    }
+    Ok(replaced)
+}
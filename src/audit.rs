@@ -0,0 +1,96 @@
+//! Classifies every shebang in a tree without changing anything, e.g. to review a third-party
+//! output before deciding whether (and how) to patch it
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+use crate::{Result, iter::is_executable, shebang::{self, EnvDialect, ShebangStyle}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShebangClass {
+    /// An absolute interpreter path in /nix/store
+    StoreAbsolute,
+    /// An absolute interpreter path anywhere else, e.g. /usr/bin/python3
+    NonStoreAbsolute,
+    /// env looks the program up on PATH whenever the script runs
+    EnvStyle,
+    /// Resolved against the working directory of whoever runs the script
+    Relative,
+    /// No interpreter, env without a program, or an absolute interpreter that doesn't exist
+    Broken,
+}
+
+impl ShebangClass {
+    pub const ALL: [ShebangClass; 5] = [Self::StoreAbsolute, Self::NonStoreAbsolute, Self::EnvStyle, Self::Relative, Self::Broken];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::StoreAbsolute => "store-absolute",
+            Self::NonStoreAbsolute => "non-store-absolute",
+            Self::EnvStyle => "env-style",
+            Self::Relative => "relative",
+            Self::Broken => "broken",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub path: PathBuf,
+    pub shebang: String,
+    pub class: ShebangClass,
+}
+
+/// Every executable file under `root` that has a shebang, sorted by path. Symlinks are not followed,
+/// the same as when patching
+pub fn audit_tree(root: &Path, dialect: EnvDialect) -> Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() || !is_executable(&entry.metadata()?) {
+            continue;
+        }
+        let mut first_line = Vec::new();
+        BufReader::new(File::open(entry.path())?).read_until(b'\n', &mut first_line)?;
+        if first_line.starts_with(b"#!") {
+            let shebang = String::from_utf8_lossy(&first_line).trim_end().to_string();
+            let class = classify(&shebang, dialect);
+            entries.push(AuditEntry { path: entry.into_path(), shebang, class });
+        }
+    }
+    Ok(entries)
+}
+
+/// Which class a shebang line falls into on this machine (whether absolute interpreters exist is checked)
+pub fn classify(line: &str, dialect: EnvDialect) -> ShebangClass {
+    let Some(parsed) = shebang::parse_with(line, dialect) else {
+        return ShebangClass::Broken;
+    };
+    let interpreter = classify_path(parsed.interpreter.text);
+    match parsed.style {
+        ShebangStyle::Direct => interpreter,
+        _ if interpreter == ShebangClass::Broken => ShebangClass::Broken,
+        ShebangStyle::EnvComplex => ShebangClass::EnvStyle,
+        ShebangStyle::Env | ShebangStyle::EnvSplit => match parsed.program() {
+            None => ShebangClass::Broken,
+            // env is only a detour then
+            Some(program) if program.text.contains('/') => classify_path(program.text),
+            Some(_) => ShebangClass::EnvStyle,
+        },
+    }
+}
+
+fn classify_path(path: &str) -> ShebangClass {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        ShebangClass::Relative
+    } else if !path.exists() {
+        ShebangClass::Broken
+    } else if path.starts_with("/nix/store") {
+        ShebangClass::StoreAbsolute
+    } else {
+        ShebangClass::NonStoreAbsolute
+    }
+}
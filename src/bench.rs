@@ -0,0 +1,107 @@
+//! Synthetic trees for measuring throughput, so performance regressions show up as numbers
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+use crate::{Options, PathResolver, Result, patch_tree};
+
+pub struct BenchConfig {
+    /// Number of files in the synthesized tree
+    pub files: usize,
+    /// Worker count for the parallel runs (every phase is also measured with 1)
+    pub jobs: usize,
+    /// Scratch directory; everything created below it is removed afterwards
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// "walk" (directory traversal only), "rewrite" (patching a fresh tree) or
+    /// "parse" (a second run over the patched tree, which reads and resolves but writes nothing)
+    pub phase: &'static str,
+    pub jobs: usize,
+    pub files: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub fn files_per_second(&self) -> f64 {
+        self.files as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+const SHEBANGS: [&str; 5] = [
+    "#!/bin/sh",
+    "#!/usr/bin/env bash",
+    "#!/usr/bin/python3 -u",
+    "#!/usr/bin/env -S bash -e",
+    "#!/usr/local/bin/bash",
+];
+
+/// Writes `files` executable files below `root`, 100 per directory. One in ten has no shebang,
+/// the rest cycle through common shebang styles using interpreters that exist in `root/bin`.
+pub fn synthesize_tree(root: &Path, files: usize) -> Result<()> {
+    for i in 0..files {
+        let dir = root.join("tree").join(format!("d{}", i / 100));
+        if i % 100 == 0 {
+            fs::create_dir_all(&dir)?;
+        }
+        let contents = match i % 10 {
+            9 => "plain data, not a script\n".to_string(),
+            n => format!("{}\necho {}\n", SHEBANGS[n % SHEBANGS.len()], i),
+        };
+        write_executable(&dir.join(format!("f{}", i)), &contents)?;
+    }
+    let bin = root.join("bin");
+    fs::create_dir_all(&bin)?;
+    for interpreter in ["sh", "bash", "python3", "env"] {
+        write_executable(&bin.join(interpreter), "#!/bin/sh\n")?;
+    }
+    Ok(())
+}
+
+fn write_executable(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Measures each phase sequentially and with `config.jobs` workers
+pub fn run_bench(config: &BenchConfig) -> Result<Vec<BenchResult>> {
+    let mut results = Vec::new();
+    let mut jobs_to_measure = vec![1];
+    if config.jobs > 1 {
+        jobs_to_measure.push(config.jobs);
+    }
+    let scratch = config.dir.join("patchShebangs-bench");
+    let outcome = (|| {
+        for &jobs in &jobs_to_measure {
+            let _ = fs::remove_dir_all(&scratch);
+            synthesize_tree(&scratch, config.files)?;
+            let tree = scratch.join("tree");
+            let resolver = PathResolver::new(scratch.join("bin").to_string_lossy());
+            let options = Options { jobs, ..Options::default() };
+
+            if jobs == 1 {
+                let started = Instant::now();
+                let entries = WalkDir::new(&tree).into_iter().filter_map(|entry| entry.ok()).count();
+                results.push(BenchResult { phase: "walk", jobs, files: entries, elapsed: started.elapsed() });
+            }
+            let started = Instant::now();
+            patch_tree(&tree, &options, &resolver)?;
+            results.push(BenchResult { phase: "rewrite", jobs, files: config.files, elapsed: started.elapsed() });
+            let started = Instant::now();
+            patch_tree(&tree, &options, &resolver)?;
+            results.push(BenchResult { phase: "parse", jobs, files: config.files, elapsed: started.elapsed() });
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&scratch);
+    outcome.map(|_| results)
+}
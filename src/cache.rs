@@ -0,0 +1,103 @@
+//! Remembers the size and mtime each file had when it was last handled, so re-runs over the
+//! same tree only look at files that changed since
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, atomic::{AtomicUsize, Ordering}},
+    time::UNIX_EPOCH,
+};
+use serde::{Deserialize, Serialize};
+use crate::{Observer, PatchedFile, Result, SkipCode, SkippedFile};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Nanoseconds since the epoch
+    pub mtime: u128,
+    pub size: u64,
+    pub result: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    settings: String,
+    files: HashMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Default)]
+pub struct StateCache {
+    settings: String,
+    previous: HashMap<PathBuf, CacheEntry>,
+    current: Mutex<HashMap<PathBuf, CacheEntry>>,
+    hits: AtomicUsize,
+}
+
+impl StateCache {
+    /// Loads the cache at `path`, starting empty if it doesn't exist, can't be parsed or was
+    /// written with different `settings` (e.g. another search path, where the old results no
+    /// longer apply)
+    pub fn load(path: &Path, settings: &str) -> Result<Self> {
+        let previous = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<CacheFile>(&text).ok()
+                .filter(|cache| cache.settings == settings)
+                .map(|cache| cache.files)
+                .unwrap_or_default(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(Self { settings: settings.to_string(), previous, ..Self::default() })
+    }
+
+    /// Whether the file is unchanged since it was last handled, in which case it is carried
+    /// over into the new cache as is
+    pub fn is_unchanged(&self, path: &Path) -> bool {
+        let Some(entry) = self.previous.get(path) else { return false };
+        if stat(path).is_some_and(|(mtime, size)| mtime == entry.mtime && size == entry.size) {
+            self.current.lock().unwrap().insert(path.to_path_buf(), entry.clone());
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How many files `is_unchanged` let through
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Writes out the files handled this run (files that errored are left out, so they are retried)
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let cache = CacheFile { settings: self.settings.clone(), files: self.current.lock().unwrap().clone() };
+        let text = serde_json::to_string(&cache).map_err(io::Error::from)?;
+        fs::write(path, text + "\n")?;
+        Ok(())
+    }
+
+    fn record(&self, path: &Path, result: &str) {
+        if let Some((mtime, size)) = stat(path) {
+            self.current.lock().unwrap().insert(path.to_path_buf(), CacheEntry { mtime, size, result: result.to_string() });
+        }
+    }
+}
+
+fn stat(path: &Path) -> Option<(u128, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some((mtime, metadata.len()))
+}
+
+impl Observer for StateCache {
+    fn file_patched(&self, patched: &PatchedFile) {
+        // stat'ed after the rewrite, so the next run sees the patched file as unchanged
+        self.record(&patched.path, "patched");
+    }
+    fn file_skipped(&self, skipped: &SkippedFile) {
+        // decided without reading the file, on things (like a chmod) its mtime doesn't change with
+        if matches!(skipped.code, SkipCode::NotExecutable | SkipCode::Excluded | SkipCode::TooLarge) {
+            return;
+        }
+        self.record(&skipped.path, skipped.code.as_str());
+    }
+}
@@ -0,0 +1,116 @@
+//! A C ABI over `patch_tree`, for build systems and language FFIs that can't link Rust directly.
+//! include/patch_shebangs.h declares everything here
+use std::{
+    cell::RefCell,
+    env,
+    ffi::{CStr, CString, c_char, c_int},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+use crate::{Options, PathResolver, PatchReport, exit::ExitCode};
+
+/// `ps_options`. All zeroes is the default: $PATH, no update, one file at a time
+#[repr(C)]
+pub struct PsOptions {
+    /// Colon-separated directories to find interpreters in; NULL for $PATH
+    pub search_path: *const c_char,
+    pub update: bool,
+    pub jobs: usize,
+}
+
+/// `ps_report`
+#[repr(C)]
+#[derive(Default)]
+pub struct PsReport {
+    pub patched: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub denied: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: Option<String>) {
+    // a message with a NUL in it is cut there rather than lost
+    let message = message.map(|message| CString::new(message).unwrap_or_else(|error| {
+        let end = error.nul_position();
+        CString::new(&error.into_vec()[..end]).unwrap_or_default()
+    }));
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Runs `body`, returning `fallback` (and the panic message as the last error) if it panics, since
+/// a panic can't unwind into the C caller
+fn guarded<T>(fallback: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        set_last_error(Some(format!("panicked: {}", message)));
+        fallback
+    })
+}
+
+/// Patches every executable script under `path`, and returns one of the CLI's exit codes
+/// (`ExitCode::code`): 0 when everything went through. `report`, unless NULL, gets the counts.
+/// Why a run failed is left for `ps_last_error`. A panic comes back as `ExitCode::Failed`
+///
+/// # Safety
+/// `path` must be a NUL-terminated string. `options` and `report` must each be NULL or point to a
+/// valid struct, and `options->search_path` NULL or a NUL-terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ps_patch_tree(path: *const c_char, options: *const PsOptions, report: *mut PsReport) -> c_int {
+    // SAFETY: passed on as the caller gave them
+    guarded(ExitCode::Failed.code().into(), || unsafe { patch_tree_unguarded(path, options, report) })
+}
+
+/// `ps_patch_tree`, which may panic
+unsafe fn patch_tree_unguarded(path: *const c_char, options: *const PsOptions, report: *mut PsReport) -> c_int {
+    set_last_error(None);
+    if path.is_null() {
+        set_last_error(Some("path is NULL".to_string()));
+        return ExitCode::Usage.code().into();
+    }
+    // SAFETY: the caller's side of the contract above
+    let (root, options) = unsafe { (CStr::from_ptr(path).to_string_lossy().to_string(), options.as_ref()) };
+    let search_path = match options.map(|options| options.search_path).filter(|search_path| !search_path.is_null()) {
+        // SAFETY: as above
+        Some(search_path) => unsafe { CStr::from_ptr(search_path) }.to_string_lossy().to_string(),
+        None => env::var("PATH").unwrap_or_default(),
+    };
+    let patch_options = Options {
+        update: options.is_some_and(|options| options.update),
+        jobs: options.map_or(0, |options| options.jobs),
+        ..Options::default()
+    };
+    let (code, counts) = match crate::patch_tree(&root, &patch_options, &PathResolver::new(search_path)) {
+        Ok(patched) => {
+            if !patched.errors.is_empty() {
+                set_last_error(Some(patched.errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")));
+            }
+            (ExitCode::for_report(&patched), counts(&patched))
+        }
+        Err(error) => {
+            set_last_error(Some(error.to_string()));
+            (ExitCode::for_error(&error), PsReport::default())
+        }
+    };
+    // SAFETY: as above
+    if let Some(report) = unsafe { report.as_mut() } {
+        *report = counts;
+    }
+    code.code().into()
+}
+
+fn counts(report: &PatchReport) -> PsReport {
+    PsReport { patched: report.patched.len(), skipped: report.skipped.len(), errors: report.errors.len(), denied: report.denied.len() }
+}
+
+/// What went wrong in the last `ps_patch_tree` call on this thread (every failed file, one per
+/// line), or NULL when nothing did. Valid until the next call
+#[unsafe(no_mangle)]
+pub extern "C" fn ps_last_error() -> *const c_char {
+    guarded(ptr::null(), || LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr())))
+}
@@ -0,0 +1,140 @@
+//! Checking resolved interpreters against the runtime closure of the output, so a shebang pointing
+//! at a store path the package doesn't reference (and that may be garbage collected, or missing on
+//! another machine) is caught at patch time instead of at run time. `TrustedResolver` is the cheaper
+//! version, which only knows the prefixes interpreters are expected under
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command as SysCommand,
+    sync::Mutex,
+};
+use crate::{PatchError, Resolver, Result, resolve::TraceFn};
+
+/// The store paths of a runtime closure
+#[derive(Debug, Default, Clone)]
+pub struct Closure {
+    store_paths: HashSet<String>,
+    /// What the closure was loaded from, for messages
+    pub source: String,
+}
+
+impl Closure {
+    /// A store path (or a link to one, like `./result`) is queried with `nix-store -q --requisites`;
+    /// any other file is read as a list of store paths
+    pub fn load(path: &Path) -> Result<Self> {
+        let canonical = fs::canonicalize(path)?;
+        let canonical_text = canonical.to_string_lossy();
+        if canonical.is_file() && store_path_of(&canonical_text) != Some(&*canonical_text) {
+            Self::read_list(path)
+        } else {
+            Self::query(path)
+        }
+    }
+
+    /// Runs `nix-store -q --requisites` on `path`
+    pub fn query(path: &Path) -> Result<Self> {
+        let output = SysCommand::new("nix-store").args(["--query", "--requisites"]).arg(path).output()
+            .map_err(|error| io::Error::new(error.kind(), format!("could not run nix-store: {}", error)))?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "nix-store --query --requisites {} failed: {}", path.display(), String::from_utf8_lossy(&output.stderr).trim(),
+            )).into());
+        }
+        Ok(Self::parse(&String::from_utf8_lossy(&output.stdout), path))
+    }
+
+    /// One store path per line. Lines that aren't store paths are ignored, so the files
+    /// `exportReferencesGraph` and `closureInfo` write work as well
+    pub fn read_list(path: &Path) -> Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?, path))
+    }
+
+    fn parse(text: &str, source: &Path) -> Self {
+        let store_paths = text.lines().filter_map(|line| store_path_of(line.trim())).map(str::to_string).collect();
+        Self { store_paths, source: source.display().to_string() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.store_paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store_paths.is_empty()
+    }
+
+    /// Whether `path` is inside one of the closure's store paths. Nothing outside the store is
+    pub fn contains(&self, path: &str) -> bool {
+        store_path_of(path).is_some_and(|store_path| self.store_paths.contains(store_path))
+    }
+}
+
+/// Passes lookups on to `inner`, failing those that resolve outside `closure` with `PatchError::OutsideClosure`
+pub struct ClosureResolver<'a, R> {
+    pub closure: &'a Closure,
+    /// When set, an interpreter outside the closure is only reported here (once per path) and used anyway
+    pub warn: Option<TraceFn>,
+    warned: Mutex<HashSet<String>>,
+    inner: R,
+}
+
+impl<'a, R: Resolver> ClosureResolver<'a, R> {
+    pub fn new(closure: &'a Closure, inner: R) -> Self {
+        Self { closure, warn: None, warned: Mutex::default(), inner }
+    }
+}
+
+impl<R: Resolver> Resolver for ClosureResolver<'_, R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let path = self.inner.resolve(program)?;
+        if self.closure.contains(&path) {
+            return Ok(path);
+        }
+        match &self.warn {
+            Some(warn) => {
+                if self.warned.lock().unwrap().insert(path.clone()) {
+                    warn(&format!("{} (for {}) is not in the closure of {}", path, program, self.closure.source));
+                }
+                Ok(path)
+            }
+            None => Err(PatchError::OutsideClosure {
+                program: program.to_string(),
+                path,
+                closure: self.closure.source.clone(),
+            }),
+        }
+    }
+}
+
+/// Passes lookups on to `inner`, handing `warn` every interpreter (once per path) that is under
+/// none of `prefixes`. Those are almost always PATH leaking in from the build environment
+pub struct TrustedResolver<R> {
+    pub prefixes: Vec<PathBuf>,
+    warn: TraceFn,
+    warned: Mutex<HashSet<String>>,
+    inner: R,
+}
+
+impl<R: Resolver> TrustedResolver<R> {
+    pub fn new(prefixes: Vec<PathBuf>, warn: TraceFn, inner: R) -> Self {
+        Self { prefixes, warn, warned: Mutex::default(), inner }
+    }
+}
+
+impl<R: Resolver> Resolver for TrustedResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let path = self.inner.resolve(program)?;
+        let trusted = self.prefixes.iter().any(|prefix| Path::new(&path).starts_with(prefix));
+        if !trusted && self.warned.lock().unwrap().insert(path.clone()) {
+            (self.warn)(&format!("{} (for {}) is not under a trusted prefix or any input of the build", path, program));
+        }
+        Ok(path)
+    }
+}
+
+/// "/nix/store/<hash>-bash-5.2/bin/bash" -> "/nix/store/<hash>-bash-5.2"
+pub(crate) fn store_path_of(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/nix/store/")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    (end > 0).then(|| &path[..("/nix/store/".len() + end)])
+}
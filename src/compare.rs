@@ -0,0 +1,60 @@
+//! Lines up the shebangs of two trees by relative path, e.g. before and after a fixup phase
+//! or two builds of the same package, to track down where they diverge
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShebangDifference {
+    OnlyInA { path: PathBuf, shebang: String },
+    OnlyInB { path: PathBuf, shebang: String },
+    Changed { path: PathBuf, a: String, b: String },
+}
+
+/// Returns the differences sorted by relative path. Files without a shebang are ignored, and a
+/// file that has one on only one side counts as being only in that tree
+pub fn compare_trees(a: &Path, b: &Path) -> Result<Vec<ShebangDifference>> {
+    let mut a = shebangs_in(a)?;
+    let b = shebangs_in(b)?;
+    let mut differences = Vec::new();
+    for (path, b_shebang) in b {
+        match a.remove(&path) {
+            Some(a_shebang) if a_shebang == b_shebang => {}
+            Some(a_shebang) => differences.push(ShebangDifference::Changed { path, a: a_shebang, b: b_shebang }),
+            None => differences.push(ShebangDifference::OnlyInB { path, shebang: b_shebang }),
+        }
+    }
+    differences.extend(a.into_iter().map(|(path, shebang)| ShebangDifference::OnlyInA { path, shebang }));
+    differences.sort_by(|x, y| x.path().cmp(y.path()));
+    Ok(differences)
+}
+
+impl ShebangDifference {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::OnlyInA { path, .. } | Self::OnlyInB { path, .. } | Self::Changed { path, .. } => path,
+        }
+    }
+}
+
+fn shebangs_in(root: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    let mut shebangs = BTreeMap::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let mut first_line = Vec::new();
+        BufReader::new(File::open(entry.path())?).read_until(b'\n', &mut first_line)?;
+        if first_line.starts_with(b"#!") {
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path()).to_path_buf();
+            shebangs.insert(relative, String::from_utf8_lossy(&first_line).trim_end().to_string());
+        }
+    }
+    Ok(shebangs)
+}
@@ -0,0 +1,182 @@
+//! A long-running server answering JSON-RPC 2.0 requests on a Unix socket, one per line, so build
+//! orchestrators running many small jobs pay neither a process spawn nor a fresh PATH search for each.
+//! Lookups are cached per search path across all connections.
+//!
+//! Methods (`path` is always optional and defaults to the daemon's search path):
+//! - `patch {root, path, update, jobs}`: runs `patch_tree`, returns what was patched, skipped counts and errors
+//! - `check {root, path, update}`: the same decisions, but nothing is written; returns the changes it would make
+//! - `resolve {program, path}`: returns `{path}` for one interpreter
+//! - `forget {}`: drops the cached lookups, for after PATH's contents changed
+//! - `shutdown {}`: stops accepting connections and returns from `serve` once the open ones close
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
+};
+use serde_json::{Value, json};
+use crate::{CachingResolver, Options, PatchError, PathResolver};
+
+// JSON-RPC's own error codes, and the one for requests that were understood but failed
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const FAILED: i64 = -32000;
+
+pub struct Daemon {
+    /// For requests that don't bring their own `path`
+    pub search_path: String,
+    resolvers: Mutex<HashMap<String, Arc<CachingResolver<PathResolver>>>>,
+    stopping: AtomicBool,
+}
+
+struct RpcError(i64, String);
+
+impl From<PatchError> for RpcError {
+    fn from(error: PatchError) -> Self {
+        RpcError(FAILED, error.to_string())
+    }
+}
+
+impl Daemon {
+    pub fn new(search_path: impl Into<String>) -> Self {
+        Self { search_path: search_path.into(), resolvers: Mutex::default(), stopping: AtomicBool::new(false) }
+    }
+
+    /// Answers every connection on its own thread until a `shutdown` request
+    pub fn serve(&self, listener: &UnixListener) -> io::Result<()> {
+        let address = listener.local_addr()?;
+        let address = &address;
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                if self.stopping.load(Ordering::Relaxed) {
+                    break;
+                }
+                let stream = stream?;
+                scope.spawn(move || {
+                    if self.answer(stream) {
+                        // accept() is blocking, so a connection wakes it up to see the flag
+                        self.stopping.store(true, Ordering::Relaxed);
+                        if let Some(path) = address.as_pathname() {
+                            let _ = UnixStream::connect(path);
+                        }
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Handles one connection's requests, and returns whether one of them was `shutdown`
+    fn answer(&self, stream: UnixStream) -> bool {
+        let Ok(mut writer) = stream.try_clone() else {
+            return false;
+        };
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (response, shutdown) = self.handle(&line);
+            if let Some(response) = response
+                && writeln!(writer, "{}", response).is_err()
+            {
+                break;
+            }
+            if shutdown {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The response to one request line (None for notifications), and whether it asked to shut down
+    pub fn handle(&self, line: &str) -> (Option<String>, bool) {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(error) => return (Some(response(&Value::Null, Err(RpcError(PARSE_ERROR, error.to_string())))), false),
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return (Some(response(&id.unwrap_or_default(), Err(RpcError(INVALID_REQUEST, "no method".to_string())))), false);
+        };
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+        let result = self.call(method, &params);
+        // requests without an id are notifications, which get no response
+        (id.map(|id| response(&id, result)), method == "shutdown")
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "patch" => {
+                let (root, options) = (string_param(params, "root")?, options(params));
+                let report = crate::patch_tree(root, &options, &*self.resolver(params))?;
+                let patched: Vec<_> = report.patched.iter().map(|patched| json!({
+                    "path": patched.path,
+                    "old_shebang": patched.old_shebang,
+                    "new_shebang": patched.new_shebang,
+                    "tokens_replaced": patched.tokens_replaced,
+                })).collect();
+                let errors: Vec<_> = report.errors.iter().map(ToString::to_string).collect();
+                Ok(json!({ "patched": patched, "skipped": report.skipped.len(), "errors": errors, "denied": report.denied }))
+            }
+            "check" => {
+                let root = string_param(params, "root")?;
+                // files on a read-only filesystem are changes too, just ones patching would fail to make
+                let options = Options { dry_run: true, continue_read_only: true, ..options(params) };
+                let report = crate::patch_tree(root, &options, &*self.resolver(params))?;
+                let changes: Vec<_> = report.patched.iter().chain(&report.read_only).map(|patched| json!({
+                    "path": patched.path,
+                    "old_shebang": patched.old_shebang,
+                    "new_shebang": patched.new_shebang,
+                })).collect();
+                let errors: Vec<_> = report.errors.iter().map(ToString::to_string).collect();
+                Ok(json!({ "changes": changes, "errors": errors }))
+            }
+            "resolve" => {
+                let program = string_param(params, "program")?;
+                Ok(json!({ "path": crate::Resolver::resolve(&*self.resolver(params), program)? }))
+            }
+            "forget" => {
+                self.resolvers.lock().unwrap().values().for_each(|resolver| resolver.clear());
+                Ok(json!({}))
+            }
+            "shutdown" => Ok(json!({})),
+            _ => Err(RpcError(METHOD_NOT_FOUND, format!("no method {}", method))),
+        }
+    }
+
+    /// The shared resolver for the request's search path
+    fn resolver(&self, params: &Value) -> Arc<CachingResolver<PathResolver>> {
+        let search_path = params.get("path").and_then(Value::as_str).unwrap_or(&self.search_path);
+        let mut resolvers = self.resolvers.lock().unwrap();
+        resolvers.entry(search_path.to_string()).or_insert_with(|| Arc::new(CachingResolver::new(PathResolver::new(search_path)))).clone()
+    }
+}
+
+fn options(params: &Value) -> Options {
+    // any client on the socket may ask, so no more threads than there are CPUs
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let jobs = params.get("jobs").and_then(Value::as_u64).unwrap_or(0).min(cpus as u64) as usize;
+    Options {
+        update: params.get("update").and_then(Value::as_bool).unwrap_or(false),
+        jobs,
+        ..Options::default()
+    }
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| RpcError(INVALID_PARAMS, format!("{} (a string) is missing", name)))
+}
+
+fn response(id: &Value, result: Result<Value, RpcError>) -> String {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(RpcError(code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+    };
+    response.to_string()
+}
@@ -0,0 +1,83 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+pub type Result<T, E = PatchError> = std::result::Result<T, E>;
+
+/// Everything that can stop `patch_tree`, split by kind so callers can match on it
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Could not find {program} in PATH={searched}{}{}", skipped_list(.skipped), .hint.as_deref().map(|h| format!("\nhint: {}", h)).unwrap_or_default())]
+    MissingInterpreter {
+        program: String,
+        /// The search path that was used
+        searched: String,
+        /// Candidates that existed but couldn't be used, with the reason
+        skipped: Vec<String>,
+        hint: Option<String>,
+    },
+    #[error("{reason} in shebang: {shebang}")]
+    UnsupportedShebang { shebang: String, reason: String },
+    #[error("interpreter path {path:?} contains whitespace, which the kernel would split the shebang at")]
+    WhitespaceInInterpreter { path: String },
+    #[error("{} is not valid UTF-8", .path.display())]
+    NonUtf8 { path: PathBuf },
+    #[error("would be patched, but is on a read-only filesystem")]
+    ReadOnly,
+    #[error("{program} resolved to {path}, which is not in the closure of {closure}")]
+    OutsideClosure { program: String, path: String, closure: String },
+    #[error("Resolver `{command}` {message} for {program}")]
+    Resolver { command: String, program: String, message: String },
+    /// Which file (and shebang, once it was read) any of the other errors happened for
+    #[error("{}: {error}{}", .path.display(), .shebang.as_deref().map(|s| format!("\n    shebang: {}", s)).unwrap_or_default())]
+    InFile {
+        path: PathBuf,
+        shebang: Option<String>,
+        error: Box<PatchError>,
+    },
+}
+
+impl PatchError {
+    /// The underlying error, without the file context
+    pub fn kind(&self) -> &PatchError {
+        match self {
+            PatchError::InFile { error, .. } => error.kind(),
+            other => other,
+        }
+    }
+
+    pub(crate) fn in_file(self, path: &Path, shebang: Option<&str>) -> Self {
+        match self {
+            PatchError::InFile { .. } => self,
+            other => PatchError::InFile {
+                path: path.to_path_buf(),
+                shebang: shebang.map(str::to_string),
+                error: Box::new(other),
+            },
+        }
+    }
+}
+
+fn skipped_list(skipped: &[String]) -> String {
+    if skipped.is_empty() {
+        String::new()
+    } else {
+        format!(" (skipped: {})", skipped.join(", "))
+    }
+}
+
+#[cfg(feature = "walk")]
+impl From<walkdir::Error> for PatchError {
+    fn from(error: walkdir::Error) -> Self {
+        // the path gets attached through in_file, so only keep the io::Error when there is one
+        // (symlink loops carry none, but are still a filesystem problem)
+        if error.io_error().is_some() {
+            PatchError::Io(error.into_io_error().unwrap())
+        } else {
+            PatchError::Io(io::Error::other(error))
+        }
+    }
+}
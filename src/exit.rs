@@ -0,0 +1,77 @@
+//! The exit codes of the command-line tool, so scripts wrapping it can tell a failed run from one
+//! that only found work to do. A run stopped by a signal exits with 128 + the signal number instead,
+//! like a shell reports it
+use crate::{PatchError, PatchReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitCode {
+    Success,
+    /// Files could not be patched, or the run couldn't start (unreadable inputs, a failing hook command, ...)
+    Failed,
+    /// The command line itself is wrong
+    Usage,
+    /// A check found something to change: `compare` found differences, `lint` found errors
+    ChangesNeeded,
+    /// Files could not be patched, all of them because an interpreter wasn't found
+    UnresolvedInterpreter,
+    /// A rewrite would change again on a second run (`Options::verify_idempotent`)
+    NotIdempotent,
+    /// Everything was patched, but the post-hook failed for some files
+    HookFailed,
+}
+
+impl ExitCode {
+    pub const ALL: [ExitCode; 7] = [
+        Self::Success, Self::Failed, Self::Usage, Self::ChangesNeeded, Self::UnresolvedInterpreter, Self::NotIdempotent, Self::HookFailed,
+    ];
+
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::Failed => 1,
+            Self::Usage => 2,
+            Self::ChangesNeeded => 3,
+            Self::UnresolvedInterpreter => 4,
+            Self::NotIdempotent => 5,
+            Self::HookFailed => 6,
+        }
+    }
+
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failed => "files could not be patched, or the run failed",
+            Self::Usage => "invalid command line",
+            Self::ChangesNeeded => "a check (compare, lint) found something to change",
+            Self::UnresolvedInterpreter => "files could not be patched, all for an interpreter that wasn't found",
+            Self::NotIdempotent => "a rewrite is not idempotent (--verify-idempotent)",
+            Self::HookFailed => "the post-hook failed",
+        }
+    }
+
+    /// `UnresolvedInterpreter` for a missing interpreter, `Failed` for anything else
+    pub fn for_error(error: &PatchError) -> Self {
+        match error.kind() {
+            PatchError::MissingInterpreter { .. } => Self::UnresolvedInterpreter,
+            _ => Self::Failed,
+        }
+    }
+
+    /// What a run that produced `report` exits with
+    pub fn for_report(report: &PatchReport) -> Self {
+        if !report.errors.is_empty() {
+            let unresolved = report.errors.iter().all(|error| Self::for_error(error) == Self::UnresolvedInterpreter);
+            if unresolved { Self::UnresolvedInterpreter } else { Self::Failed }
+        } else if !report.unstable.is_empty() {
+            Self::NotIdempotent
+        } else {
+            Self::Success
+        }
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        code.code().into()
+    }
+}
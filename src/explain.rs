@@ -0,0 +1,85 @@
+//! Walks through every decision `patch_tree` would make for one file, without changing it, to answer
+//! "why did (or didn't) this get patched?"
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+use crate::{Options, Resolver, Result, guile, iter::is_executable, nixshell, process::{LinePlan, Outcome, plan_line}, shebang};
+
+/// Hands `step` one line per decision, in order. A `PathResolver` with `trace` set adds the
+/// candidates it probes in between
+pub fn explain_file<R: Resolver + ?Sized>(path: &Path, options: &Options, resolver: &R, step: &mut dyn FnMut(&str)) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        step("a symlink: the walk doesn't follow symlinks, so only its target is patched (when it is inside the tree)");
+        return Ok(());
+    }
+    if !metadata.is_file() {
+        step("not a regular file: never patched");
+        return Ok(());
+    }
+    if !is_executable(&metadata) {
+        step("a regular file, but not executable: never looked at");
+        return Ok(());
+    }
+    step("a regular executable file");
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut first_line = Vec::new();
+    reader.read_until(b'\n', &mut first_line)?;
+    let continuation = if nixshell::wanted(&first_line, options) { nixshell::read_continuation(&mut reader)? } else { Vec::new() };
+    let line = String::from_utf8_lossy(&first_line).trim_end().to_string();
+    if first_line.starts_with(b"#!") {
+        step(&format!("shebang: {}", line));
+        for line in String::from_utf8_lossy(&continuation).lines() {
+            step(&format!("followed by: {}", line));
+        }
+        match shebang::parse_with(&line, options.env_dialect) {
+            Some(parsed) => {
+                let args: Vec<_> = parsed.args.iter().map(|arg| arg.text).collect();
+                step(&format!("parsed as {:?} ({:?} dialect): interpreter {}, arguments {:?}", parsed.style, options.env_dialect, parsed.interpreter.text, args));
+                if let Some(program) = parsed.program() {
+                    step(&format!("the program to look up is {}", Path::new(program.text).file_name().and_then(|name| name.to_str()).unwrap_or(program.text)));
+                }
+                if guile::has_meta_switch(&parsed) {
+                    let mut rest = String::new();
+                    reader.read_to_string(&mut rest)?;
+                    match guile::meta_lines(&rest) {
+                        Some(lines) => step(&format!("guile's meta switch: its arguments are {:?}, up to the !# line, and the \\ is kept", lines.join(" "))),
+                        None => step("guile's meta switch, but no !# line ends the block after it (guile won't be able to read the script)"),
+                    }
+                }
+            }
+            None => step("parsed: no interpreter at all"),
+        }
+    } else if first_line.contains(&0) {
+        step("result: skipped [binary]: a NUL byte in the first line");
+        return Ok(());
+    } else {
+        step("no shebang in the first line");
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if let Some((_, program)) = options.add_missing_shebang.iter().find(|(rule, _)| Some(rule.as_str()) == extension) {
+            step(&format!("result: a shebang for {} would be added", program));
+            return Ok(());
+        }
+    }
+
+    match plan_line(path, first_line, &continuation, options, resolver) {
+        Ok(LinePlan::Done(Outcome::Skipped(code))) => step(&format!("result: skipped [{}]: {}", code.as_str(), code.description())),
+        Ok(LinePlan::Done(Outcome::Malformed(problem))) => step(&format!("result: skipped [malformed]: {}", problem)),
+        Ok(LinePlan::Done(_)) => {}
+        Ok(LinePlan::Rewrite { new, skip_reason: Some(code), .. }) => {
+            let tokens: Vec<_> = options.tokens.iter().map(|(token, _)| token.as_str()).collect();
+            step(&format!("result: the shebang stays {} [{}], but the body is still searched for {}", new, code.as_str(), tokens.join(", ")));
+        }
+        Ok(LinePlan::Rewrite { new, skip_reason: None, continuation, .. }) => {
+            step(&format!("result: would be rewritten to {}", new));
+            for line in continuation.iter().flat_map(|continuation| continuation.new.lines()) {
+                step(&format!("followed by: {}", line));
+            }
+        }
+        Err(error) => step(&format!("result: would fail: {}", error)),
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Indirect GC roots for the store paths shebangs were pointed at, so a development tree patched
+//! outside a derivation (which nothing else keeps alive) doesn't break on the next garbage collection
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command as SysCommand,
+    sync::Mutex,
+};
+use crate::{Resolver, Result, closure::store_path_of};
+
+/// The distinct store paths of every interpreter resolved so far
+#[derive(Debug, Default)]
+pub struct GcRoots {
+    store_paths: Mutex<BTreeSet<String>>,
+}
+
+impl GcRoots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the store path `interpreter` is in. Interpreters outside the store need no root
+    pub fn record(&self, interpreter: &str) {
+        if let Some(store_path) = store_path_of(interpreter) {
+            self.store_paths.lock().unwrap().insert(store_path.to_string());
+        }
+    }
+
+    pub fn store_paths(&self) -> Vec<String> {
+        self.store_paths.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Links `dir/<hash>-<name>` to each store path with `nix-store --add-root --indirect`, which also
+    /// registers the link with the garbage collector. Returns the links
+    pub fn register(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        fs::create_dir_all(dir)?;
+        let mut links = Vec::new();
+        for store_path in self.store_paths() {
+            let link = dir.join(Path::new(&store_path).file_name().unwrap_or_default());
+            let output = SysCommand::new("nix-store").arg("--add-root").arg(&link).args(["--indirect", "--realise"]).arg(&store_path).output()
+                .map_err(|error| io::Error::new(error.kind(), format!("could not run nix-store: {}", error)))?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "nix-store --add-root {} failed: {}", link.display(), String::from_utf8_lossy(&output.stderr).trim(),
+                )).into());
+            }
+            links.push(link);
+        }
+        Ok(links)
+    }
+}
+
+/// Passes lookups on to `inner`, adding the store path of what it finds to `roots`
+pub struct RootingResolver<'a, R> {
+    pub roots: &'a GcRoots,
+    inner: R,
+}
+
+impl<'a, R: Resolver> RootingResolver<'a, R> {
+    pub fn new(roots: &'a GcRoots, inner: R) -> Self {
+        Self { roots, inner }
+    }
+}
+
+impl<R: Resolver> Resolver for RootingResolver<'_, R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let path = self.inner.resolve(program)?;
+        self.roots.record(&path);
+        Ok(path)
+    }
+}
@@ -0,0 +1,92 @@
+//! Every shebang change as a patch `git apply` (or `patch -p1`) understands, so the changes can be
+//! reviewed first, or made on another machine
+use std::{
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+use crate::{PatchedFile, Result};
+
+// what git diff uses by default
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Default)]
+pub struct GitPatch {
+    text: Vec<u8>,
+    /// Changes a patch of the first lines can't express, and why
+    pub left_out: Vec<(PathBuf, &'static str)>,
+}
+
+impl GitPatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file that was just patched. The context lines are read back from it, so this has to
+    /// happen before anything else changes the file
+    pub fn record(&mut self, patched: &PatchedFile) -> Result<()> {
+        let left_out = if patched.wrapped.is_some() {
+            Some("wrapped scripts are not in the patch")
+        } else if patched.tokens_replaced > 0 {
+            Some("replaced tokens are not in the patch")
+        } else if !patched.path.is_file() {
+            Some("archive members are not in the patch")
+        } else {
+            None
+        };
+        if let Some(reason) = left_out {
+            self.left_out.push((patched.path.clone(), reason));
+            return Ok(());
+        }
+
+        let contents = fs::read(&patched.path)?;
+        let lines: Vec<&[u8]> = contents.split_inclusive(|&b| b == b'\n').collect();
+        let first = lines.first().copied().unwrap_or_default();
+        let context = lines.get(1..lines.len().min(1 + CONTEXT)).unwrap_or_default();
+        // an added shebang is a line of its own
+        let added = patched.old_shebang.is_empty();
+        let old_count = context.len() + usize::from(!added);
+
+        let name = patch_path(&patched.path);
+        let _ = writeln!(self.text, "diff --git a/{0} b/{0}", name);
+        let _ = writeln!(self.text, "--- a/{}", name);
+        let _ = writeln!(self.text, "+++ b/{}", name);
+        let _ = writeln!(self.text, "@@ -{},{} +1,{} @@", usize::from(old_count > 0), old_count, context.len() + 1);
+        if !added {
+            // the rest of the line (line ending, trailing whitespace) stays as it was
+            let rest = first.get(patched.new_shebang.len()..).unwrap_or_default();
+            self.line(b'-', &[patched.old_shebang.as_bytes(), rest].concat());
+        }
+        self.line(b'+', first);
+        for line in context {
+            self.line(b' ', line);
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, prefix: u8, line: &[u8]) {
+        self.text.push(prefix);
+        self.text.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            self.text.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, &self.text)?;
+        Ok(())
+    }
+}
+
+/// Relative to the working directory, which is where `git apply` expects to be run
+fn patch_path(path: &Path) -> String {
+    let path = match std::env::current_dir() {
+        Ok(cwd) if path.is_absolute() => path.strip_prefix(&cwd).unwrap_or(path),
+        _ => path,
+    };
+    let parts: Vec<_> = path.components().filter_map(|component| match component {
+        Component::Normal(part) => Some(part.to_string_lossy()),
+        _ => None,
+    }).collect();
+    parts.join("/")
+}
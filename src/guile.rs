@@ -0,0 +1,46 @@
+//! Guile's meta switch: a script starting `#!/path/to/guile \` takes guile's real arguments from the
+//! lines after the shebang, up to a `!#` line (where the `#!...!#` block comment guile reads the whole
+//! header as ends). Only a lone `\` does this, so rewrites have to keep it exactly that, however the
+//! shebang is spelled afterwards
+use std::path::Path;
+use crate::shebang::{Shebang, ShebangStyle};
+
+/// As the kernel passes it to guile
+pub(crate) const META_SWITCH: &str = "\\";
+/// Inside `env -S`, where a backslash starts an escape
+pub(crate) const ESCAPED_META_SWITCH: &str = "\\\\";
+
+/// guile, or a versioned name like guile3.0 or guile-2.2
+fn is_guile(program: &str) -> bool {
+    let name = Path::new(program).file_name().and_then(|name| name.to_str()).unwrap_or(program);
+    name.strip_prefix("guile").is_some_and(|version| version.trim_start_matches('-').chars().all(|c| c.is_ascii_digit() || c == '.'))
+}
+
+/// Whether `parsed` runs guile with the meta switch as its only argument. `#!/usr/bin/env guile \` counts
+/// too: Linux hands env `guile \` as one word, but the BSD and macOS kernels split it, so it works there
+pub(crate) fn has_meta_switch(parsed: &Shebang<'_>) -> bool {
+    let Some(program) = parsed.program() else {
+        return false;
+    };
+    let rest = match parsed.style {
+        ShebangStyle::Direct => &parsed.args[..],
+        ShebangStyle::Env | ShebangStyle::EnvSplit => parsed.program_arg.map_or(&[][..], |index| &parsed.args[index + 1..]),
+        ShebangStyle::EnvComplex => return false,
+    };
+    let switch = if parsed.style == ShebangStyle::EnvSplit { ESCAPED_META_SWITCH } else { META_SWITCH };
+    is_guile(program.text) && matches!(rest, [arg] if arg.text == switch)
+}
+
+/// The argument lines of the meta block in `rest` (the script after its shebang line), without the `!#`
+/// line. `None` when no `!#` line ends the block, which guile would fail to read
+#[cfg(feature = "walk")]
+pub(crate) fn meta_lines(rest: &str) -> Option<Vec<&str>> {
+    let mut lines = Vec::new();
+    for line in rest.lines() {
+        if line.trim_end() == "!#" {
+            return Some(lines);
+        }
+        lines.push(line);
+    }
+    None
+}
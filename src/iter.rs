@@ -0,0 +1,346 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+use walkdir::{DirEntry, WalkDir};
+use crate::{Options, PatchError, PatchedFile, Resolver, Result, Rewrite, SkipCode, SkippedFile, process::{Outcome, process_file}, rewrite_line, timings::{Phase, timed}};
+
+/// Something that happened to one file during a run
+#[derive(Debug)]
+pub enum PatchEvent {
+    /// A regular executable file is about to be inspected
+    Started(PathBuf),
+    Patched(PatchedFile),
+    Skipped(SkippedFile),
+    /// Something looks wrong with the file, but processing continues
+    Warning { path: PathBuf, message: String },
+    /// The patched shebang would change again on a second run (only checked with `verify_idempotent`)
+    Unstable(PathBuf),
+    /// The file could not be patched; the run continues
+    Error(PatchError),
+    /// The file would have been patched, but is on a read-only filesystem (EROFS, e.g. a mounted
+    /// /nix/store). With `Options::continue_read_only` only; without it, the run aborts with `PatchError::ReadOnly`
+    ReadOnly(PatchedFile),
+    /// A file or directory could not be read or replaced for lack of permissions (EACCES/EPERM),
+    /// and was skipped. Without `Options::strict` only; with it, the run aborts instead
+    Denied(PathBuf),
+}
+
+/// What the walk found next
+pub(crate) enum Walked {
+    /// With the file's leading bytes, when the scan already read them
+    File(PathBuf, Option<Vec<u8>>),
+    Denied(PathBuf),
+    /// A regular file left alone without being opened
+    Skipped(PathBuf, SkipCode),
+}
+
+enum Entry {
+    Walked(DirEntry),
+    Denied(PathBuf),
+    /// A regular file `Options::filter` rejected
+    Excluded(PathBuf),
+}
+
+/// Walks a tree lazily, patching one file per step, so huge trees can be processed with bounded memory.
+/// Yields `Err` once (and then stops) when an I/O error aborts the walk.
+pub struct PatchIter<'a, R: Resolver + ?Sized> {
+    walker: walkdir::IntoIter,
+    options: &'a Options,
+    resolver: &'a R,
+    // the events of the file currently being processed
+    pending: VecDeque<PatchEvent>,
+    // a file whose `Started` is out, processed on the next step
+    started: Option<(PathBuf, Option<Vec<u8>>)>,
+    done: bool,
+    cancelled: bool,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    scanner: Option<crate::uring::Scanner>,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    scanned: VecDeque<Result<Walked>>,
+}
+
+impl<'a, R: Resolver + ?Sized> PatchIter<'a, R> {
+    pub fn new(root: impl AsRef<Path>, options: &'a Options, resolver: &'a R) -> Self {
+        Self {
+            // by name, so runs over the same tree report files in the same order whatever the filesystem
+            walker: WalkDir::new(root).sort_by_file_name().into_iter(),
+            options,
+            resolver,
+            pending: VecDeque::new(),
+            started: None,
+            done: false,
+            cancelled: false,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            scanner: crate::uring::Scanner::new(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            scanned: VecDeque::new(),
+        }
+    }
+
+    /// Whether the walk ended early because `Options::cancel` was set
+    pub fn was_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Walks to the next regular file (or unreadable entry). Returns Ok(None) once the walk is over (or cancelled).
+    pub(crate) fn next_file(&mut self) -> Result<Option<Walked>> {
+        let options = self.options;
+        timed(options, Phase::Traversal, || self.walk_to_next_file())
+    }
+
+    fn walk_to_next_file(&mut self) -> Result<Option<Walked>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.scanner.is_some() {
+            return self.next_scanned();
+        }
+        loop {
+            let entry = match self.next_entry()? {
+                None => return Ok(None),
+                Some(Entry::Denied(path)) => return Ok(Some(Walked::Denied(path))),
+                Some(Entry::Excluded(path)) => return Ok(Some(Walked::Skipped(path, SkipCode::Excluded))),
+                Some(Entry::Walked(entry)) => entry,
+            };
+            let file_path = entry.path();
+
+            // Only regular executable files get opened
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let metadata = entry.metadata().map_err(|e| PatchError::from(e).in_file(file_path, None))?;
+            let skipped = if !is_executable(&metadata) {
+                Some(SkipCode::NotExecutable)
+            } else if is_too_large(metadata.len(), self.options) {
+                Some(SkipCode::TooLarge)
+            } else {
+                None
+            };
+            return Ok(Some(match skipped {
+                Some(code) => Walked::Skipped(entry.into_path(), code),
+                None => Walked::File(entry.into_path(), None),
+            }));
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        loop {
+            if let Some(cancel) = &self.options.cancel
+                && cancel.load(Ordering::Relaxed)
+            {
+                self.cancelled = true;
+                return Ok(None);
+            }
+            let Some(entry) = self.walker.next() else {
+                return Ok(None);
+            };
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    let path = error.path().map(Path::to_path_buf);
+                    if let Some(path) = &path
+                        && is_denied(error.io_error(), self.options)
+                    {
+                        return Ok(Some(Entry::Denied(path.clone())));
+                    }
+                    let error = PatchError::from(error);
+                    return Err(match path {
+                        Some(path) => error.in_file(&path, None),
+                        None => error,
+                    });
+                }
+            };
+            let is_dir = entry.file_type().is_dir();
+            if self.options.filter.as_ref().is_none_or(|filter| filter.accept(entry.path(), is_dir)) {
+                return Ok(Some(Entry::Walked(entry)));
+            }
+            // a rejected directory takes everything below it along
+            if is_dir {
+                self.walker.skip_current_dir();
+            } else if entry.file_type().is_file() {
+                return Ok(Some(Entry::Excluded(entry.into_path())));
+            }
+        }
+    }
+
+    /// `next_file` with the stat and the first read of a batch of files going through io_uring
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn next_scanned(&mut self) -> Result<Option<Walked>> {
+        loop {
+            if let Some(cancel) = &self.options.cancel
+                && cancel.load(Ordering::Relaxed)
+            {
+                self.cancelled = true;
+                return Ok(None);
+            }
+            if let Some(scanned) = self.scanned.pop_front() {
+                return scanned.map(Some);
+            }
+            // a denied entry or error ends the batch, so everything stays in walk order
+            // excluded files stay in the batch, for their place in it, but aren't probed
+            let mut batch = Vec::new();
+            let mut last = None;
+            while batch.len() < crate::uring::BATCH {
+                match self.next_entry() {
+                    Ok(None) => break,
+                    Ok(Some(Entry::Walked(entry))) if entry.file_type().is_file() => batch.push((entry.into_path(), false)),
+                    Ok(Some(Entry::Walked(_))) => {}
+                    Ok(Some(Entry::Excluded(path))) => batch.push((path, true)),
+                    Ok(Some(Entry::Denied(path))) => {
+                        last = Some(Ok(Walked::Denied(path)));
+                        break;
+                    }
+                    Err(error) => {
+                        last = Some(Err(error));
+                        break;
+                    }
+                }
+            }
+            if batch.is_empty() && last.is_none() {
+                return Ok(None);
+            }
+            let paths: Vec<&Path> = batch.iter().filter(|(_, excluded)| !excluded).map(|(path, _)| path.as_path()).collect();
+            let mut probes = self.scanner.as_mut().unwrap().probe(&paths)?.into_iter();
+            for (path, excluded) in batch {
+                if excluded {
+                    self.scanned.push_back(Ok(Walked::Skipped(path, SkipCode::Excluded)));
+                    continue;
+                }
+                let probe = probes.next().unwrap();
+                match probe.mode {
+                    Ok(mode) if mode & 0o100 == 0 => self.scanned.push_back(Ok(Walked::Skipped(path, SkipCode::NotExecutable))),
+                    Ok(_) if is_too_large(probe.size, self.options) => self.scanned.push_back(Ok(Walked::Skipped(path, SkipCode::TooLarge))),
+                    Ok(_) => self.scanned.push_back(Ok(Walked::File(path, probe.head))),
+                    Err(error) if is_denied(Some(&error), self.options) => self.scanned.push_back(Ok(Walked::Denied(path))),
+                    Err(error) => self.scanned.push_back(Err(PatchError::from(error).in_file(&path, None))),
+                }
+            }
+            self.scanned.extend(last);
+        }
+    }
+
+    /// Announces the next file, or processes the one announced last, queueing the events. Returns Ok(false) once the walk is over.
+    fn step(&mut self) -> Result<bool> {
+        if let Some((file_path, head)) = self.started.take() {
+            self.pending.extend(file_events(&file_path, head.as_deref(), self.options, self.resolver)?);
+            return Ok(true);
+        }
+        match self.next_file()? {
+            Some(Walked::File(file_path, head)) => {
+                // handed out before the file is touched, so observers really hear of it first
+                self.pending.push_back(PatchEvent::Started(file_path.clone()));
+                self.started = Some((file_path, head));
+            }
+            Some(Walked::Denied(path)) => self.pending.push_back(PatchEvent::Denied(path)),
+            Some(Walked::Skipped(path, code)) => self.pending.push_back(PatchEvent::Skipped(SkippedFile::new(&path, code))),
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o100 != 0
+}
+
+// Windows has no executable bit, but Git Bash, MSYS2 and WSL still honor shebangs,
+// so every regular file is a candidate (files without one are skipped as usual). WASI has none either
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+fn is_too_large(size: u64, options: &Options) -> bool {
+    options.max_size.is_some_and(|max_size| size > max_size)
+}
+
+fn is_denied(error: Option<&std::io::Error>, options: &Options) -> bool {
+    !options.strict && error.is_some_and(|error| error.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
+/// Everything that happens to one file after its `Started`, in order. Errs only on I/O errors, which abort the run.
+/// `head` is the file's first bytes, when the walk already read them
+pub(crate) fn file_events<R: Resolver + ?Sized>(file_path: &Path, head: Option<&[u8]>, options: &Options, resolver: &R) -> Result<Vec<PatchEvent>> {
+    let outcome = timed(options, Phase::Classification, || process_file(file_path, head, options, resolver));
+    outcome_events(file_path, outcome, options, resolver)
+}
+
+/// The events for how handling one file turned out
+pub(crate) fn outcome_events<R: Resolver + ?Sized>(file_path: &Path, outcome: Result<Outcome>, options: &Options, resolver: &R) -> Result<Vec<PatchEvent>> {
+    let mut events = Vec::new();
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(error) => {
+            let error = error.in_file(file_path, None);
+            if let PatchError::Io(io_error) = error.kind() {
+                if is_denied(Some(io_error), options) {
+                    events.push(PatchEvent::Denied(file_path.to_path_buf()));
+                    return Ok(events);
+                }
+                return Err(error);
+            }
+            events.push(PatchEvent::Error(error));
+            return Ok(events);
+        }
+    };
+    match outcome {
+        Outcome::Patched { old, new, hashes, tokens_replaced, wrapped } => {
+            let verification = if options.verify_idempotent { verify(file_path, &new, options, resolver) } else { Vec::new() };
+            events.push(PatchEvent::Patched(PatchedFile { path: file_path.to_path_buf(), old_shebang: old, new_shebang: new, hashes, tokens_replaced, wrapped }));
+            events.extend(verification);
+        }
+        Outcome::Skipped(code) => {
+            events.push(PatchEvent::Skipped(SkippedFile::new(file_path, code)));
+        }
+        Outcome::ReadOnly { old, new } => {
+            if !options.continue_read_only {
+                return Err(PatchError::ReadOnly.in_file(file_path, Some(&old)));
+            }
+            events.push(PatchEvent::ReadOnly(PatchedFile { path: file_path.to_path_buf(), old_shebang: old, new_shebang: new, hashes: None, tokens_replaced: 0, wrapped: None }));
+        }
+        Outcome::Malformed(problem) => {
+            let message = format!("{}, skipping", problem);
+            events.push(PatchEvent::Warning { path: file_path.to_path_buf(), message });
+            events.push(PatchEvent::Skipped(SkippedFile { path: file_path.to_path_buf(), code: SkipCode::Malformed, reason: problem }));
+        }
+    }
+    Ok(events)
+}
+
+/// Re-runs detection on what a fresh read of the patched file would yield
+fn verify<R: Resolver + ?Sized>(path: &Path, new_shebang: &str, options: &Options, resolver: &R) -> Vec<PatchEvent> {
+    let reread = new_shebang.trim_end();
+    match rewrite_line(reread, options, resolver) {
+        Ok(Rewrite::Line(again)) if again != reread => vec![
+            PatchEvent::Warning { path: path.to_path_buf(), message: format!("rewrite is not idempotent: {} -> {}", reread, again) },
+            PatchEvent::Unstable(path.to_path_buf()),
+        ],
+        Ok(_) => Vec::new(),
+        Err(error) => vec![PatchEvent::Error(error.in_file(path, Some(reread)))],
+    }
+}
+
+impl<R: Resolver + ?Sized> Iterator for PatchIter<'_, R> {
+    type Item = Result<PatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! An append-only record of finished files, flushed after each one, so an interrupted
+//! or crashed run can be resumed without reprocessing everything
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use serde::{Deserialize, Serialize};
+use crate::{Observer, PatchError, PatchedFile, Result, SkippedFile};
+
+#[derive(Serialize, Deserialize)]
+struct JournalLine {
+    path: PathBuf,
+    result: String,
+}
+
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Starts a fresh journal, replacing any existing one
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self { file: Mutex::new(File::create(path)?) })
+    }
+
+    /// Reopens an existing journal for appending, returning the files it already lists
+    pub fn resume(path: &Path) -> Result<(Self, HashSet<PathBuf>)> {
+        let mut done = HashSet::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                // the last line may be torn if the previous run was killed mid-write
+                if let Ok(entry) = serde_json::from_str::<JournalLine>(&line?) {
+                    done.insert(entry.path);
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok((Self { file: Mutex::new(file) }, done))
+    }
+
+    fn append(&self, path: &Path, result: &str) {
+        let line = serde_json::to_string(&JournalLine { path: path.to_path_buf(), result: result.to_string() }).unwrap();
+        let mut file = self.file.lock().unwrap();
+        // a journal that can't be written only costs work on resume, so don't fail the run over it
+        let _ = writeln!(file, "{}", line).and_then(|_| file.flush());
+    }
+
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Observer for Journal {
+    fn file_patched(&self, patched: &PatchedFile) {
+        self.append(&patched.path, "patched");
+    }
+    fn file_skipped(&self, skipped: &SkippedFile) {
+        self.append(&skipped.path, "skipped");
+    }
+    fn file_errored(&self, path: &Path, _error: &PatchError) {
+        self.append(path, "error");
+    }
+}
@@ -0,0 +1,507 @@
+//! Patches script interpreter paths (shebangs) to point at interpreters found on a given PATH
+// based on: https://github.com/NixOS/nixpkgs/blob/master/pkgs/stdenv/generic/make-derivation.nix # commit/d3afbb6da92399220987b8fbb1165c4a2f1a7b5c
+use std::{
+    path::Path,
+    sync::{Arc, atomic::AtomicBool},
+    time::SystemTime,
+};
+
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "walk")]
+pub mod audit;
+#[cfg(feature = "walk")]
+pub mod bench;
+#[cfg(feature = "reports")]
+pub mod cache;
+#[cfg(feature = "capi")]
+mod capi;
+pub mod closure;
+#[cfg(feature = "walk")]
+pub mod compare;
+#[cfg(all(feature = "walk", feature = "reports", unix))]
+pub mod daemon;
+#[cfg(feature = "walk")]
+pub mod explain;
+mod error;
+pub mod exit;
+pub mod gcroot;
+#[cfg(feature = "reports")]
+pub mod gitpatch;
+mod guile;
+#[cfg(feature = "walk")]
+mod iter;
+#[cfg(feature = "reports")]
+pub mod journal;
+#[cfg(feature = "walk")]
+pub mod lint;
+mod lock;
+#[cfg(feature = "walk")]
+mod parallel;
+#[cfg(feature = "reports")]
+pub mod manifest;
+#[cfg(feature = "reports")]
+pub mod metrics;
+#[cfg(feature = "walk")]
+mod nixshell;
+#[cfg(feature = "archive")]
+pub mod oci;
+#[cfg(feature = "walk")]
+mod process;
+#[cfg(feature = "reports")]
+pub mod provenance;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "reports")]
+pub mod replay;
+mod report;
+pub mod resolve;
+#[cfg(feature = "reports")]
+pub mod sbom;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod shebang;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+mod throttle;
+mod timings;
+pub mod vendor;
+
+pub use error::{PatchError, Result};
+#[cfg(feature = "walk")]
+pub use iter::{PatchEvent, PatchIter};
+pub use lock::RootLock;
+pub use report::{ContentHashes, PatchReport, PatchedFile, SkipCode, SkippedFile};
+pub use shebang::EnvDialect;
+use shebang::ShebangStyle;
+pub use throttle::Throttle;
+pub use timings::{Phase, Timings};
+pub use resolve::{AliasResolver, CachingResolver, CommandResolver, FhsResolver, MappingResolver, PathResolver, ResolveStrategy, Resolver, SymlinkPolicy, VersionRequirement};
+
+/// Lets embedders decide per walked entry whether it gets processed,
+/// e.g. to only touch files listed in a build system's manifest.
+pub trait FileFilter: Sync {
+    /// Returning false for a directory skips everything below it
+    fn accept(&self, path: &Path, is_dir: bool) -> bool;
+}
+
+impl<F: Fn(&Path, bool) -> bool + Sync> FileFilter for F {
+    fn accept(&self, path: &Path, is_dir: bool) -> bool {
+        self(path, is_dir)
+    }
+}
+
+/// Receives progress events while `patch_tree` runs, so GUIs and build daemons
+/// can surface what is happening without parsing stdout. Every method defaults to a no-op.
+/// Always called from the thread running `patch_tree`, even with `Options::jobs`.
+pub trait Observer: Send + Sync {
+    /// A regular executable file is about to be inspected
+    fn file_started(&self, _path: &Path) {}
+    fn file_patched(&self, _patched: &PatchedFile) {}
+    fn file_skipped(&self, _skipped: &SkippedFile) {}
+    /// Something looks wrong with the file, but processing continues
+    fn warning(&self, _path: &Path, _message: &str) {}
+    /// The file could not be patched (I/O errors also abort the run)
+    fn file_errored(&self, _path: &Path, _error: &PatchError) {}
+}
+
+// lets callers keep a handle on an observer they give to Options, e.g. to read counters afterwards
+impl<O: Observer + ?Sized> Observer for Arc<O> {
+    fn file_started(&self, path: &Path) {
+        (**self).file_started(path)
+    }
+    fn file_patched(&self, patched: &PatchedFile) {
+        (**self).file_patched(patched)
+    }
+    fn file_skipped(&self, skipped: &SkippedFile) {
+        (**self).file_skipped(skipped)
+    }
+    fn warning(&self, path: &Path, message: &str) {
+        (**self).warning(path, message)
+    }
+    fn file_errored(&self, path: &Path, error: &PatchError) {
+        (**self).file_errored(path, error)
+    }
+}
+
+/// Forwards every event to each observer in turn
+impl Observer for Vec<Box<dyn Observer>> {
+    fn file_started(&self, path: &Path) {
+        self.iter().for_each(|o| o.file_started(path))
+    }
+    fn file_patched(&self, patched: &PatchedFile) {
+        self.iter().for_each(|o| o.file_patched(patched))
+    }
+    fn file_skipped(&self, skipped: &SkippedFile) {
+        self.iter().for_each(|o| o.file_skipped(skipped))
+    }
+    fn warning(&self, path: &Path, message: &str) {
+        self.iter().for_each(|o| o.warning(path, message))
+    }
+    fn file_errored(&self, path: &Path, error: &PatchError) {
+        self.iter().for_each(|o| o.file_errored(path, error))
+    }
+}
+
+#[cfg(feature = "walk")]
+struct NoObserver;
+#[cfg(feature = "walk")]
+impl Observer for NoObserver {}
+
+/// What to do when a resolved interpreter path contains whitespace, which the kernel would split
+/// the shebang at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SpacesPolicy {
+    /// Fail the file with `PatchError::WhitespaceInInterpreter`
+    #[default]
+    Error,
+    /// Run the interpreter through `env -S` with its path quoted
+    EnvSplit,
+}
+
+/// What to do with the `#! nix-shell -i INTERPRETER -p PACKAGES...` lines following a
+/// `#!/usr/bin/env nix-shell` shebang
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NixShellPolicy {
+    /// Patch the shebang to the nix-shell found and each `-i` interpreter to a resolved path, so the
+    /// script still runs in its nix-shell environment, with the interpreter the package was built with
+    #[default]
+    Interpreter,
+    /// Only patch the shebang, leaving the lines after it alone
+    FirstLine,
+    /// Point the shebang straight at the resolved `-i` interpreter, for installed scripts whose
+    /// dependencies are in place without nix-shell. The nix-shell lines stay behind as comments
+    Direct,
+}
+
+/// How many files may wait between the stages of the parallel pipeline (the walk, classifying files,
+/// rewriting them and reporting the results), so memory stays flat however far ahead the walk is of
+/// slow writes. `None` is twice `Options::jobs`, and every queue has room for at least one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueDepths {
+    pub classify: Option<usize>,
+    pub rewrite: Option<usize>,
+    pub report: Option<usize>,
+}
+
+/// The mtime a rewritten file ends up with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MtimePolicy {
+    /// Keep the original's, so make-style up-to-date checks don't see a change
+    #[default]
+    Preserve,
+    /// Leave the time of the rewrite, e.g. to trigger downstream rebuild detection
+    Fresh,
+    /// The same for every rewritten file, e.g. for deterministic archives
+    Fixed(SystemTime),
+}
+
+#[derive(Default)]
+pub struct Options {
+    /// Also re-patch shebangs that already point into /nix/store
+    pub update: bool,
+    /// After patching, check that re-running would not change the shebang again
+    pub verify_idempotent: bool,
+    /// Compute sha256 hashes of patched files before and after the rewrite
+    pub hash_contents: bool,
+    pub filter: Option<Box<dyn FileFilter>>,
+    pub observer: Option<Box<dyn Observer>>,
+    /// Checked between files; once set the run stops early (the file in flight is always finished)
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// `(token, program)` pairs: every occurrence of the token anywhere in a script (a file with a
+    /// shebang) is replaced with the resolved path of the program, e.g. `@python3@` for scripts
+    /// that re-exec themselves through an embedded interpreter path
+    pub tokens: Vec<(String, String)>,
+    /// Leave scripts byte-identical: move each one to `.name-wrapped` and put a small `sh` wrapper
+    /// in its place that runs it with the resolved interpreter. `tokens` are not replaced in this mode
+    pub wrap_instead: bool,
+    /// Number of files processed at once; 0 and 1 both process them one by one on the calling thread,
+    /// as does any number on WebAssembly targets without threads
+    pub jobs: usize,
+    pub queue_depths: QueueDepths,
+    /// Rewrite `env -S prog arg` to `prog arg` directly, for macOS releases whose env predates -S.
+    /// Shebangs passing prog several arguments are an error, since Linux hands them over as one
+    pub compat_macos: bool,
+    /// How env options in shebangs are read, so `-iS` and `-P altpath` are understood on BSD trees
+    pub env_dialect: EnvDialect,
+    /// Abort on files and directories that can't be read or replaced for lack of permissions,
+    /// instead of skipping them (`PatchReport::denied`)
+    pub strict: bool,
+    /// Report files on a read-only filesystem that would be patched (`PatchReport::read_only`) and
+    /// keep going, instead of aborting at the first one
+    pub continue_read_only: bool,
+    /// fsync every rewritten file and its directory, so a snapshot taken right after the run never
+    /// sees a torn write
+    pub fsync: bool,
+    /// Limits how many bytes per second are read and written while patching
+    pub throttle: Option<Throttle>,
+    /// Point shebangs at `busybox APPLET` when busybox is all that provides an interpreter (or is what
+    /// a canonicalized interpreter path turns out to be), as in minimal container images
+    pub busybox: bool,
+    pub spaces: SpacesPolicy,
+    /// `(extension, program)` pairs, e.g. `("py", "python3")`: executable files with that extension
+    /// but no shebang get one pointing at the resolved program. Ignored with `wrap_instead`
+    pub add_missing_shebang: Vec<(String, String)>,
+    /// Collects how long each phase of the run took, per thread
+    pub timings: Option<Timings>,
+    pub mtime: MtimePolicy,
+    pub nix_shell: NixShellPolicy,
+    /// Files larger than this many bytes are skipped (`SkipCode::TooLarge`) without being opened
+    pub max_size: Option<u64>,
+    /// Decide and report everything as usual, but leave every file as it is: files that would be
+    /// patched are reported as `Patched` (with the tokens that would be replaced counted)
+    pub dry_run: bool,
+}
+
+#[cfg(feature = "walk")]
+impl Options {
+    fn observer(&self) -> &dyn Observer {
+        self.observer.as_deref().unwrap_or(&NoObserver)
+    }
+}
+
+/// Patches every executable script under `root`.
+/// Problems with individual scripts (unresolvable interpreters, unsupported shebangs, ...)
+/// are collected in the report; I/O errors abort the walk.
+#[cfg(feature = "walk")]
+pub fn patch_tree<R: Resolver + ?Sized>(root: impl AsRef<Path>, options: &Options, resolver: &R) -> Result<PatchReport> {
+    match &options.timings {
+        Some(timings) => walk_tree(root.as_ref(), options, &timings::TimedResolver { timings, inner: resolver }),
+        None => walk_tree(root.as_ref(), options, resolver),
+    }
+}
+
+#[cfg(feature = "walk")]
+fn walk_tree<R: Resolver + ?Sized>(root: &Path, options: &Options, resolver: &R) -> Result<PatchReport> {
+    let observer = options.observer();
+    let mut report = PatchReport::default();
+    let mut handle = |event| dispatch(event, observer, &mut report);
+    // wasm32-wasip1 can't start threads
+    let threads = !cfg!(target_family = "wasm") || cfg!(target_feature = "atomics");
+    let walked = if options.jobs > 1 && threads {
+        parallel::patch_events(root, options, resolver, &mut handle)
+    } else {
+        let mut events = PatchIter::new(root, options, resolver);
+        events.try_for_each(|event| event.map(&mut handle)).map(|_| events.was_cancelled())
+    };
+    match walked {
+        Ok(cancelled) => {
+            report.interrupted = cancelled;
+            Ok(report)
+        }
+        Err(error) => {
+            if let PatchError::InFile { path, .. } = &error {
+                observer.file_errored(path, &error);
+            }
+            Err(error)
+        }
+    }
+}
+
+#[cfg(feature = "walk")]
+fn dispatch(event: PatchEvent, observer: &dyn Observer, report: &mut PatchReport) {
+    match event {
+        PatchEvent::Started(path) => observer.file_started(&path),
+        PatchEvent::Patched(patched) => {
+            observer.file_patched(&patched);
+            report.patched.push(patched);
+        }
+        PatchEvent::Skipped(skipped) => {
+            observer.file_skipped(&skipped);
+            report.skipped.push(skipped);
+        }
+        PatchEvent::Warning { path, message } => observer.warning(&path, &message),
+        PatchEvent::Unstable(path) => report.unstable.push(path),
+        PatchEvent::ReadOnly(would_patch) => {
+            observer.warning(&would_patch.path, &format!("read-only filesystem, would patch to {}", would_patch.new_shebang));
+            report.read_only.push(would_patch);
+        }
+        PatchEvent::Denied(path) => {
+            observer.warning(&path, "permission denied, skipping");
+            report.denied.push(path);
+        }
+        PatchEvent::Error(error) => {
+            if let PatchError::InFile { path, .. } = &error {
+                observer.file_errored(path, &error);
+            }
+            report.errors.push(error);
+        }
+    }
+}
+
+enum Rewrite {
+    Line(String),
+    /// The shebang can't be interpreted; the message says why (only reported when walking)
+    #[cfg_attr(not(feature = "walk"), allow(dead_code))]
+    Malformed(String),
+}
+
+/// Computes the replacement for the first line of a file, without any filesystem access of its own
+/// (only the resolver may look around), so it can be fuzzed or property tested directly.
+/// `line` may include its line break; the replacement never does.
+/// Returns `None` when the line should be left alone: it is not a shebang, it is already what
+/// `resolver` would produce, or it is too malformed to patch (no interpreter, `env` without a program).
+/// Unlike `patch_tree` without `update`, shebangs already pointing into /nix/store are rewritten too.
+pub fn rewrite_shebang<R: Resolver + ?Sized>(line: &[u8], resolver: &R) -> Result<Option<Vec<u8>>> {
+    if !line.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let line = std::str::from_utf8(line).map_err(|_| PatchError::UnsupportedShebang {
+        shebang: String::from_utf8_lossy(line).trim_end().to_string(),
+        reason: "Invalid UTF-8".to_string(),
+    })?;
+    let original = line.trim_end();
+    match rewrite_line(original, &Options::default(), resolver)? {
+        Rewrite::Line(new) if new != original => Ok(Some(new.into_bytes())),
+        _ => Ok(None),
+    }
+}
+
+/// The resolved interpreter, plus the applet to run when it is a busybox multi-call binary
+fn resolve_interpreter<R: Resolver + ?Sized>(program: &str, options: &Options, resolver: &R) -> Result<Vec<String>> {
+    let resolved = resolver.resolve(program);
+    if !options.busybox || program == "busybox" {
+        return resolved.map(|path| vec![path]);
+    }
+    match resolved {
+        // a canonicalized /bin/sh -> busybox link only works when busybox is told the applet
+        Ok(path) if Path::new(&path).file_name().is_some_and(|name| name == "busybox") => Ok(vec![path, program.to_string()]),
+        Err(error @ PatchError::MissingInterpreter { .. }) => match resolver.resolve("busybox") {
+            Ok(busybox) if resolve::busybox_has_applet(Path::new(&busybox), program) => Ok(vec![busybox, program.to_string()]),
+            _ => Err(error),
+        },
+        other => other.map(|path| vec![path]),
+    }
+}
+
+fn has_whitespace(path: &str) -> bool {
+    path.contains(char::is_whitespace)
+}
+
+/// The kernel splits a shebang at the first whitespace, so a path containing some can only be run
+/// through `env -S`, which understands quotes
+fn quote_for_env_split(path: &str, options: &Options) -> Result<String> {
+    if options.spaces == SpacesPolicy::Error || options.compat_macos {
+        return Err(PatchError::WhitespaceInInterpreter { path: path.to_string() });
+    }
+    Ok(format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$")))
+}
+
+/// `#!/path/to/env -S "/path with spaces/prog" args...`
+fn env_split_line<R: Resolver + ?Sized>(interpreter: &[String], args: &[&str], options: &Options, resolver: &R) -> Result<String> {
+    let quoted = quote_for_env_split(&interpreter[0], options)?;
+    let env_path = resolve_env(resolver)?;
+    let all_args = [env_path.as_str(), "-S", quoted.as_str()].into_iter()
+        .chain(interpreter[1..].iter().map(String::as_str))
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>();
+    Ok(format!("#!{}", all_args.join(" ")))
+}
+
+/// env itself always has to be a path the kernel can run
+fn resolve_env<R: Resolver + ?Sized>(resolver: &R) -> Result<String> {
+    let env_path = resolver.resolve("env")?;
+    if has_whitespace(&env_path) {
+        return Err(PatchError::WhitespaceInInterpreter { path: env_path });
+    }
+    Ok(env_path)
+}
+
+// without env -S, the kernel would hand the applet and its arguments to busybox as one argument
+fn busybox_with_arguments(shebang: &str) -> PatchError {
+    PatchError::UnsupportedShebang { shebang: shebang.to_string(), reason: "busybox applet with arguments".to_string() }
+}
+
+/// Computes the replacement for a shebang line, without touching the file
+fn rewrite_line<R: Resolver + ?Sized>(original_shebang: &str, options: &Options, resolver: &R) -> Result<Rewrite> {
+    let Some(parsed) = shebang::parse_with(original_shebang, options.env_dialect) else {
+        return Ok(Rewrite::Malformed("shebang has no interpreter".to_string()));
+    };
+    let args: Vec<&str> = parsed.args.iter().map(|arg| arg.text).collect();
+    let meta_switch = guile::has_meta_switch(&parsed);
+
+    let new_interpreter_line = match parsed.style {
+        ShebangStyle::EnvSplit => {
+            let Some(program_arg) = parsed.program_arg.filter(|&index| index < args.len()) else {
+                return Err(PatchError::UnsupportedShebang { shebang: original_shebang.to_string(), reason: "Invalid -S usage".to_string() });
+            };
+            let mut interpreter = resolve_interpreter(args[program_arg], options, resolver)?;
+            let prog_args = &args[program_arg + 1..];
+            if has_whitespace(&interpreter[0]) {
+                interpreter[0] = quote_for_env_split(&interpreter[0], options)?;
+            }
+            let prog_path = interpreter.join(" ");
+            if options.compat_macos {
+                if prog_args.len() > 1 {
+                    return Err(PatchError::UnsupportedShebang { shebang: original_shebang.to_string(), reason: "More than one argument after env -S (not portable without -S)".to_string() });
+                }
+                if interpreter.len() > 1 && !prog_args.is_empty() {
+                    return Err(busybox_with_arguments(original_shebang));
+                }
+                // outside of -S, the kernel passes the meta switch on as it is written
+                let prog_args = if meta_switch { &[guile::META_SWITCH][..] } else { prog_args };
+                let all_args = std::iter::once(prog_path.as_str()).chain(prog_args.iter().copied()).collect::<Vec<_>>();
+                return Ok(Rewrite::Line(format!("#!{}", all_args.join(" "))));
+            }
+            // BSD's -P only says where env looks the program up, and that is an absolute path now
+            let mut env_options = Vec::new();
+            let mut env_args = args[..program_arg].iter();
+            while let Some(&option) = env_args.next() {
+                if option == "-P" {
+                    env_args.next();
+                } else if !option.starts_with("-P") {
+                    env_options.push(option);
+                }
+            }
+            let env_path = resolve_env(resolver)?;
+            let all_args = std::iter::once(env_path.as_str())
+                .chain(env_options)
+                .chain(std::iter::once(prog_path.as_str()))
+                .chain(prog_args.iter().copied())
+                .collect::<Vec<_>>();
+            format!("#!{}", all_args.join(" "))
+        }
+        ShebangStyle::EnvComplex => {
+            return Err(PatchError::UnsupportedShebang { shebang: original_shebang.to_string(), reason: "Unsupported env usage".to_string() });
+        }
+        ShebangStyle::Env => {
+            let Some(prog) = parsed.program() else {
+                return Ok(Rewrite::Malformed("malformed shebang (env without a program)".to_string()));
+            };
+            let interpreter = resolve_interpreter(prog.text, options, resolver)?;
+            // any other arguments are dropped, but without the meta switch guile would skip its arguments in the block below
+            if has_whitespace(&interpreter[0]) {
+                let args = if meta_switch { &[guile::ESCAPED_META_SWITCH][..] } else { &[] };
+                return Ok(Rewrite::Line(env_split_line(&interpreter, args, options, resolver)?));
+            }
+            let args = if meta_switch { &[guile::META_SWITCH][..] } else { &[] };
+            if interpreter.len() > 1 && !args.is_empty() {
+                return Err(busybox_with_arguments(original_shebang));
+            }
+            let all_args = interpreter.iter().map(String::as_str).chain(args.iter().copied()).collect::<Vec<_>>();
+            format!("#!{}", all_args.join(" "))
+        }
+        ShebangStyle::Direct => {
+            let interpreter = parsed.interpreter.text;
+            let base = Path::new(interpreter)
+                .file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or(interpreter);
+
+            let resolved = resolve_interpreter(base, options, resolver)?;
+            if has_whitespace(&resolved[0]) {
+                let args = if meta_switch { vec![guile::ESCAPED_META_SWITCH] } else { args };
+                return Ok(Rewrite::Line(env_split_line(&resolved, &args, options, resolver)?));
+            }
+            if resolved.len() > 1 && !args.is_empty() {
+                return Err(busybox_with_arguments(original_shebang));
+            }
+            let all_args = resolved.iter().map(String::as_str).chain(args.iter().copied()).collect::<Vec<_>>();
+            format!("#!{}", all_args.join(" "))
+        }
+    };
+
+    Ok(Rewrite::Line(new_interpreter_line))
+}
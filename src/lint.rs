@@ -0,0 +1,117 @@
+//! Script hygiene checks on shebangs, each with a stable rule ID, so problems can be found (and
+//! allowed in CI by ID) without patching anything
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+use crate::{Resolver, Result, iter::is_executable, shebang::{self, EnvDialect, ShebangStyle}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// A `sh` script that contains a bash-only construct
+    Bashism,
+    /// `env` without `-S` but with arguments after the program, which Linux passes to env as one
+    EnvWithoutSplit,
+    /// The interpreter can't be found on the search path, so patching would fail
+    MissingInterpreter,
+    /// Longer than the 127 bytes older kernels read of a shebang line (later ones read 255)
+    LongLine,
+}
+
+impl Rule {
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Bashism => "SB001",
+            Self::EnvWithoutSplit => "SB002",
+            Self::MissingInterpreter => "SB003",
+            Self::LongLine => "SB004",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            Self::Bashism | Self::LongLine => Severity::Warning,
+            Self::EnvWithoutSplit | Self::MissingInterpreter => Severity::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub rule: Rule,
+    pub message: String,
+}
+
+// obvious enough that a match is almost never a false positive
+const BASHISMS: [&str; 6] = ["[[ ", "function ", "source ", "declare ", "shopt ", "<<<"];
+
+/// Runs every rule on the executable scripts under `root`, in path order
+pub fn lint_tree<R: Resolver + ?Sized>(root: &Path, dialect: EnvDialect, resolver: &R) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() || !is_executable(&entry.metadata()?) {
+            continue;
+        }
+        let mut reader = BufReader::new(File::open(entry.path())?);
+        let mut first_line = Vec::new();
+        reader.read_until(b'\n', &mut first_line)?;
+        if !first_line.starts_with(b"#!") {
+            continue;
+        }
+        let line = String::from_utf8_lossy(&first_line).trim_end().to_string();
+        let mut found = |rule, message| findings.push(Finding { path: entry.path().to_path_buf(), rule, message });
+
+        if line.len() > 127 {
+            found(Rule::LongLine, format!("shebang is {} bytes long", line.len()));
+        }
+        let Some(parsed) = shebang::parse_with(&line, dialect) else {
+            continue;
+        };
+        if parsed.style == ShebangStyle::Env && parsed.args.len() > 1 {
+            found(Rule::EnvWithoutSplit, format!("env gets `{}` as a single program name; use env -S", parsed.args.iter().map(|arg| arg.text).collect::<Vec<_>>().join(" ")));
+        }
+        let Some(program) = parsed.program().map(|program| Path::new(program.text)) else {
+            continue;
+        };
+        let name = program.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if !program.starts_with("/nix/store") && !name.is_empty() && resolver.resolve(name).is_err() {
+            found(Rule::MissingInterpreter, format!("{} is not on the search path", name));
+        }
+        if name == "sh"
+            && let Some(bashism) = find_bashism(&mut reader)?
+        {
+            found(Rule::Bashism, format!("line {} uses `{}` but the script runs with sh", bashism.0, bashism.1.trim_end()));
+        }
+    }
+    Ok(findings)
+}
+
+/// The first line (numbered from the shebang) containing a bashism, ignoring comments
+fn find_bashism(reader: &mut impl BufRead) -> Result<Option<(usize, &'static str)>> {
+    let mut line = Vec::new();
+    for number in 2.. {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let code = String::from_utf8_lossy(&line);
+        let code = code.trim_start();
+        if code.starts_with('#') {
+            continue;
+        }
+        if let Some(bashism) = BASHISMS.into_iter().find(|bashism| code.contains(bashism)) {
+            return Ok(Some((number, bashism)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,32 @@
+use std::{fs::File, path::Path};
+use crate::Result;
+
+/// Advisory lock on a root, so concurrent runs (e.g. parallel build phases) don't interleave writes.
+/// The root itself is flock'ed, so no lock file has to be left behind in the output.
+/// Released when dropped. Windows can't lock a directory handle and WASI has no locks, so there this is a no-op.
+#[derive(Debug)]
+pub struct RootLock {
+    _file: Option<File>,
+}
+
+impl RootLock {
+    /// Blocks until the lock is free, calling `on_wait` once if it has to wait
+    #[cfg(not(any(windows, target_os = "wasi")))]
+    pub fn acquire(root: &Path, on_wait: impl FnOnce()) -> Result<Self> {
+        let file = File::open(root).map_err(|e| crate::PatchError::from(e).in_file(root, None))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                on_wait();
+                file.lock()?;
+            }
+            Err(std::fs::TryLockError::Error(error)) => return Err(crate::PatchError::from(error).in_file(root, None)),
+        }
+        Ok(Self { _file: Some(file) })
+    }
+
+    #[cfg(any(windows, target_os = "wasi"))]
+    pub fn acquire(_root: &Path, _on_wait: impl FnOnce()) -> Result<Self> {
+        Ok(Self { _file: None })
+    }
+}
@@ -1,139 +1,1287 @@
-// based on: https://github.com/NixOS/nixpkgs/blob/master/pkgs/stdenv/generic/make-derivation.nix # commit/d3afbb6da92399220987b8fbb1165c4a2f1a7b5c
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
+use clap_complete::Shell;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::{
-    env,
-    ffi::OsString,
-    fs::{self, File},
-    io::{BufRead, BufReader, Write},
-    os::unix::fs::PermissionsExt,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    fs,
+    io,
     path::{Path, PathBuf},
     process::Command as SysCommand,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use walkdir::WalkDir;
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use patch_shebangs::{archive::{is_archive, patch_archive}, audit::{ShebangClass, audit_tree}, exit::ExitCode, explain::explain_file, gcroot::{GcRoots, RootingResolver}, gitpatch::GitPatch, lint::{Severity, lint_tree}, bench::{BenchConfig, run_bench}, cache::StateCache, closure::{Closure, ClosureResolver, TrustedResolver}, compare::{ShebangDifference, compare_trees}, journal::Journal, manifest::{Applied, Manifest}, metrics::Metrics, oci::patch_image, provenance::Provenance, replay::{RecordingResolver, ReplayResolver, Resolutions}, sbom::Sbom, vendor::VendoringResolver, AliasResolver, CommandResolver, EnvDialect, FhsResolver, Phase, Throttle, Timings, FileFilter, MappingResolver, MtimePolicy, NixShellPolicy, Observer, Options, PatchError, PatchedFile, PathResolver, QueueDepths, ResolveStrategy, SkippedFile, RootLock, Resolver, SpacesPolicy, SymlinkPolicy, VersionRequirement, patch_tree};
 
-fn main() -> Result<()> {
-    let matches = Command::new("patchShebangs")
+/// Prints a line to stdout, or holds it back for `--stable-output`. Lines that belong together go in one call
+macro_rules! say {
+    ($($arg:tt)*) => {
+        print_entry(format!($($arg)*))
+    };
+}
+
+/// With `--stable-output`, everything `say!` printed, for sorting at exit
+static STABLE_OUTPUT: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+fn print_entry(entry: String) {
+    match STABLE_OUTPUT.lock().unwrap().as_mut() {
+        Some(entries) => entries.push(entry),
+        None => println!("{}", entry),
+    }
+}
+
+/// Prints what `--stable-output` held back: sorted, and with paths relative to the working directory
+fn flush_stable_output() {
+    let Some(entries) = STABLE_OUTPUT.lock().unwrap().take() else {
+        return;
+    };
+    let cwd = env::current_dir().map(|cwd| cwd.to_string_lossy().to_string()).unwrap_or_default();
+    let mut entries: Vec<_> = entries.into_iter().map(|entry| match cwd.as_str() {
+        "" | "/" => entry,
+        cwd => entry.replace(&format!("{}/", cwd), "").replace(&format!("\"{}\"", cwd), "\".\""),
+    }).collect();
+    entries.sort();
+    for entry in entries {
+        println!("{}", entry);
+    }
+}
+
+fn main() -> std::process::ExitCode {
+    let result = try_main();
+    flush_stable_output();
+    match result {
+        Ok(()) => ExitCode::Success.into(),
+        Err(error) => {
+            // what returning the error from main would print
+            eprintln!("Error: {:?}", error);
+            exit_code(&error).into()
+        }
+    }
+}
+
+/// A failure that exits with something more specific than `ExitCode::Failed`
+#[derive(Debug)]
+struct Failure(ExitCode, String);
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+fn exit_code(error: &anyhow::Error) -> ExitCode {
+    if let Some(Failure(code, _)) = error.downcast_ref() {
+        return *code;
+    }
+    error.downcast_ref().map(ExitCode::for_error).unwrap_or(ExitCode::Failed)
+}
+
+fn try_main() -> Result<()> {
+    let matches = cli().get_matches();
+    if matches.get_flag("stable-output") {
+        *STABLE_OUTPUT.lock().unwrap() = Some(Vec::new());
+    }
+
+    match matches.subcommand() {
+        Some(("compare", compare)) => return compare_command(compare),
+        Some(("apply", apply)) => return apply_command(apply),
+        Some(("audit", audit)) => return audit_command(audit),
+        Some(("lint", lint)) => return lint_command(lint),
+        Some(("explain", explain)) => return explain_command(explain),
+        Some(("bench", bench)) => return bench_command(bench),
+        Some(("oci", oci)) => return oci_command(oci),
+        Some(("daemon", daemon)) => return daemon_command(daemon),
+        Some(("completions", completions)) => {
+            let shell = *completions.get_one::<Shell>("shell").unwrap();
+            clap_complete::generate(shell, &mut cli(), env!("CARGO_BIN_NAME"), &mut io::stdout());
+            return Ok(());
+        }
+        Some(("mangen", _)) => {
+            clap_mangen::Man::new(cli().name(env!("CARGO_BIN_NAME"))).render(&mut io::stdout())?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let started = Instant::now();
+    let inputs = input_paths(&matches)?;
+    // the same switches a stdenv setup hook honors
+    let skip_variable = matches.get_one::<String>("skip-env").unwrap();
+    if env::var(skip_variable).is_ok_and(|value| !value.is_empty()) {
+        say!("${} is set, not patching anything", skip_variable);
+        return Ok(());
+    }
+    let skipped_subtrees: Vec<PathBuf> = env::var(matches.get_one::<String>("skip-for-env").unwrap()).unwrap_or_default()
+        .split_whitespace()
+        .flat_map(|subpath| inputs.iter().map(move |root| Path::new(root).join(subpath)))
+        // the interpreters copied there are not the tree's own scripts
+        .chain(matches.get_one::<String>("vendor-into").into_iter().flat_map(|dir| inputs.iter().map(move |root| Path::new(root).join(dir))))
+        .collect();
+    let path_env = search_path(&matches, None)?;
+    let aliases = interpreter_aliases(&matches)?;
+
+    let printer = Arc::new(PrintObserver {
+        print_hashes: matches.get_flag("hash"),
+        post_hook: matches.get_one::<String>("post-hook").cloned(),
+        hook_failures: AtomicUsize::new(0),
+        patched_by_dir: matches.get_flag("group-by-dir").then(Mutex::default),
+        verbosity: verbosity(&matches),
+    });
+
+    // the first SIGINT/SIGTERM lets the file in flight finish and the outputs get written, a second one kills us
+    let cancel = Arc::new(AtomicBool::new(false));
+    let received_signal = Arc::new(AtomicUsize::new(0));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 128 + signal, cancel.clone())?;
+        signal_hook::flag::register(signal, cancel.clone())?;
+        signal_hook::flag::register_usize(signal, received_signal.clone(), signal as usize)?;
+    }
+
+    if matches.contains_id("io-nice") {
+        lower_priority();
+    }
+
+    let mut observers: Vec<Box<dyn Observer>> = vec![Box::new(printer.clone())];
+    let mut done = HashSet::new();
+    let journal_path = matches.get_one::<String>("journal").map(Path::new);
+    if let Some(journal_path) = journal_path {
+        if matches.get_flag("resume") {
+            let (journal, already_done) = Journal::resume(journal_path)?;
+            if !already_done.is_empty() {
+                say!("Resuming, skipping {} already processed file(s)", already_done.len());
+            }
+            observers.push(Box::new(journal));
+            done = already_done;
+        } else {
+            observers.push(Box::new(Journal::create(journal_path)?));
+        }
+    }
+    let cache_path = matches.get_one::<String>("cache-file").map(Path::new);
+    let cache = match cache_path {
+        Some(cache_path) => {
+            let cache = Arc::new(StateCache::load(cache_path, &cache_settings(&matches, &path_env, &aliases))?);
+            observers.push(Box::new(cache.clone()));
+            Some(cache)
+        }
+        None => None,
+    };
+    let newer_than = matches.get_one::<SystemTime>("newer-than").copied();
+    let git_changed = match matches.get_one::<String>("git-changed") {
+        Some(reference) => Some(git_changed_files(inputs.iter(), reference)?),
+        None => None,
+    };
+    let git_tracked = if matches.get_flag("git-tracked") {
+        let mut tracked = HashSet::new();
+        for root in &inputs {
+            tracked.extend(git_files(root, &["ls-files", "--cached"])?);
+        }
+        Some(tracked)
+    } else {
+        None
+    };
+    let filter: Option<Box<dyn FileFilter>> = if done.is_empty() && cache.is_none() && newer_than.is_none() && git_changed.is_none() && git_tracked.is_none() && skipped_subtrees.is_empty() {
+        None
+    } else {
+        let cache = cache.clone();
+        Some(Box::new(move |path: &Path, is_dir: bool| {
+            if skipped_subtrees.iter().any(|subtree| path.starts_with(subtree)) {
+                return false;
+            }
+            let too_old = || newer_than.is_some_and(|cutoff| {
+                fs::metadata(path).and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified <= cutoff)
+            });
+            let unchanged_in_git = || git_changed.as_ref().is_some_and(|changed| !changed.contains(path));
+            let untracked = || git_tracked.as_ref().is_some_and(|tracked| !tracked.contains(path));
+            is_dir || !(done.contains(path) || too_old() || unchanged_in_git() || untracked() || cache.as_ref().is_some_and(|cache| cache.is_unchanged(path)))
+        }))
+    };
+
+    let options = Options {
+        update: matches.get_flag("update"),
+        verify_idempotent: matches.get_flag("verify-idempotent"),
+        hash_contents: matches.get_flag("hash") || matches.contains_id("manifest") || matches.contains_id("provenance"),
+        observer: Some(Box::new(observers)),
+        filter,
+        cancel: Some(cancel.clone()),
+        wrap_instead: matches.get_flag("wrap-instead"),
+        tokens: matches.get_many::<(String, String)>("replace-token").unwrap_or_default().cloned().collect(),
+        jobs: jobs_or_cpus(*matches.get_one::<usize>("jobs").unwrap()),
+        queue_depths: queue_depths(&matches),
+        compat_macos: matches.get_flag("compat-macos"),
+        env_dialect: *matches.get_one::<EnvDialect>("env-dialect").unwrap(),
+        strict: matches.get_flag("strict"),
+        continue_read_only: matches.get_flag("continue-read-only"),
+        fsync: matches.get_flag("fsync"),
+        throttle: matches.get_one::<u64>("io-nice").map(|&rate| Throttle::new(rate)),
+        busybox: matches.get_flag("busybox"),
+        spaces: *matches.get_one::<SpacesPolicy>("spaces").unwrap(),
+        nix_shell: *matches.get_one::<NixShellPolicy>("nix-shell").unwrap(),
+        add_missing_shebang: matches.get_many::<(String, String)>("add-missing-shebang").unwrap_or_default().cloned().collect(),
+        timings: matches.get_flag("timings").then(Timings::new),
+        mtime: match matches.get_one::<u64>("set-mtime") {
+            Some(&seconds) => MtimePolicy::Fixed(UNIX_EPOCH + Duration::from_secs(seconds)),
+            None if matches.get_flag("no-preserve-mtime") => MtimePolicy::Fresh,
+            None => MtimePolicy::Preserve,
+        },
+        max_size: matches.get_one::<u64>("max-size").copied(),
+        dry_run: false,
+    };
+
+    let archives = matches.get_flag("archive");
+    let paths = existing_inputs(inputs.iter().collect(), matches.get_flag("ignore-missing"))?;
+    let paths = dedupe_inputs(paths, |path| archives && path.is_file() && is_archive(path));
+    if paths.is_empty() {
+        say!("No input paths left to patch");
+        return Ok(());
+    }
+    let resolved: Vec<PathBuf> = paths.iter().map(fs::canonicalize).collect::<io::Result<_>>()?;
+    say!("Patching script interpreter paths in {:?}", resolved);
+    let paths_for = root_search_paths(&matches, &resolved);
+
+    let mut manifest = Manifest::new();
+    let mut git_patch = matches.contains_id("emit-patch").then(GitPatch::new);
+    let mut sbom = Sbom::default();
+    let mut provenance = Provenance::new(env::args().collect(), paths.iter().map(|p| p.to_string()).collect(), path_env.clone());
+    let mut metrics = Metrics::default();
+    let mut unresolved = 0;
+    let recorded = matches.contains_id("record").then(Resolutions::new);
+    let replay_path = matches.get_one::<String>("replay");
+    let replayed = match replay_path {
+        Some(replay_path) => Some(Resolutions::load(Path::new(replay_path)).with_context(|| format!("could not read {}", replay_path))?),
+        None => None,
+    };
+    let closure = match matches.get_one::<String>("closure") {
+        Some(closure_path) => Some(Closure::load(Path::new(closure_path)).with_context(|| format!("could not load the closure of {}", closure_path))?),
+        None => None,
+    };
+    let trusted = trusted_prefixes(&matches);
+    let gc_roots = matches.contains_id("gc-root").then(GcRoots::new);
+    let run = (|| -> Result<()> {
+        for (&path, root) in paths.iter().zip(&resolved) {
+            let path_env = match paths_for.get(root) {
+                Some(root_path) => search_path(&matches, Some(root_path))?,
+                None => path_env.clone(),
+            };
+            let _lock = if matches.get_flag("no-lock") {
+                None
+            } else {
+                Some(RootLock::acquire(Path::new(path), || eprintln!("waiting for another patchShebangs run on {}", path))?)
+            };
+            let resolver = match matches.get_one::<String>("pre-hook") {
+                Some(hook) => {
+                    let (mut dirs, mappings) = run_pre_hook(hook, path)?;
+                    dirs.extend(env::split_paths(&path_env));
+                    let resolver = build_resolver(&matches, env::join_paths(dirs)?.to_string_lossy().to_string());
+                    if mappings.is_empty() { resolver } else { Box::new(MappingResolver::new(mappings, resolver)) }
+                }
+                None => build_resolver(&matches, path_env),
+            };
+            let resolver: Box<dyn Resolver> = if aliases.is_empty() { resolver } else { Box::new(AliasResolver::new(aliases.clone(), resolver)) };
+            let substitutions: HashMap<_, _> = matches.get_many::<(String, String)>("substitute").unwrap_or_default().cloned().collect();
+            let resolver: Box<dyn Resolver> = if substitutions.is_empty() { resolver } else { Box::new(AliasResolver::new(substitutions, resolver)) };
+            let resolver: Box<dyn Resolver + '_> = match (&replayed, &recorded) {
+                (Some(replayed), _) => Box::new(ReplayResolver::new(replayed, replay_path.unwrap().as_str())),
+                (None, Some(recorded)) => Box::new(RecordingResolver::new(recorded, resolver)),
+                (None, None) => resolver,
+            };
+            let resolver: Box<dyn Resolver + '_> = match &closure {
+                Some(closure) => {
+                    let mut checked = ClosureResolver::new(closure, resolver);
+                    if matches.get_flag("closure-warn") {
+                        checked.warn = Some(Box::new(|message: &str| eprintln!("warning: {}", message)));
+                    }
+                    Box::new(checked)
+                }
+                None => resolver,
+            };
+            let resolver: Box<dyn Resolver + '_> = match &trusted {
+                Some(prefixes) => Box::new(TrustedResolver::new(prefixes.clone(), Box::new(|message: &str| eprintln!("warning: {}", message)), resolver)),
+                None => resolver,
+            };
+            let resolver: Box<dyn Resolver + '_> = match &gc_roots {
+                Some(gc_roots) => Box::new(RootingResolver::new(gc_roots, resolver)),
+                None => resolver,
+            };
+            // after the checks, which are about the interpreters themselves rather than their copies
+            let resolver: Box<dyn Resolver + '_> = match matches.get_one::<String>("vendor-into") {
+                Some(dir) => {
+                    let base = if root.is_dir() { root.as_path() } else { root.parent().unwrap_or(root) };
+                    let mut vendoring = VendoringResolver::new(base.join(dir), resolver);
+                    vendoring.symlink = matches.get_flag("vendor-symlink");
+                    Box::new(vendoring)
+                }
+                None => resolver,
+            };
+            let report = if matches.get_flag("archive") && Path::new(path).is_file() && is_archive(Path::new(path)) {
+                patch_archive(Path::new(path), &options, &resolver)?
+            } else {
+                patch_tree(path, &options, &resolver)?
+            };
+            for patched in &report.patched {
+                manifest.record(patched);
+                sbom.record(patched);
+                provenance.record(patched);
+                if let Some(git_patch) = &mut git_patch {
+                    git_patch.record(patched)?;
+                }
+            }
+            metrics.record(&report);
+            unresolved += report.errors.iter().filter(|error| ExitCode::for_error(error) == ExitCode::UnresolvedInterpreter).count();
+            if report.interrupted {
+                break;
+            }
+        }
+        Ok(())
+    })();
+
+    // written even when the run failed or was interrupted, so they describe what actually changed
+    if let Some(manifest_path) = matches.get_one::<String>("manifest") {
+        manifest.save(Path::new(manifest_path))?;
+    }
+    if let (Some(git_patch), Some(patch_path)) = (&git_patch, matches.get_one::<String>("emit-patch")) {
+        for (path, reason) in &git_patch.left_out {
+            eprintln!("warning: {}: {}", path.display(), reason);
+        }
+        git_patch.save(Path::new(patch_path))?;
+    }
+    if let Some(sbom_path) = matches.get_one::<String>("sbom") {
+        sbom.save(Path::new(sbom_path))?;
+    }
+    if let (Some(recorded), Some(record_path)) = (&recorded, matches.get_one::<String>("record")) {
+        recorded.save(Path::new(record_path))?;
+    }
+    if let (Some(gc_roots), Some(root_dir)) = (&gc_roots, matches.get_one::<String>("gc-root")) {
+        let links = gc_roots.register(Path::new(root_dir))?;
+        say!("{} GC root(s) in {}", links.len(), root_dir);
+    }
+    if let Some(provenance_path) = matches.get_one::<String>("provenance") {
+        provenance.save(Path::new(provenance_path))?;
+    }
+    if let Some(metrics_path) = matches.get_one::<String>("metrics-file") {
+        metrics.save(Path::new(metrics_path), started.elapsed())?;
+    }
+    if let (Some(cache), Some(cache_path)) = (&cache, cache_path) {
+        if cache.hits() > 0 {
+            say!("{} unchanged file(s) skipped (--cache-file)", cache.hits());
+        }
+        cache.save(cache_path)?;
+    }
+    run?;
+    if let Some(timings) = &options.timings
+        && !matches.get_flag("stable-output")
+    {
+        print_timings(timings);
+    }
+    if let Some(patched_by_dir) = &printer.patched_by_dir {
+        print_patched_by_dir(&patched_by_dir.lock().unwrap());
+    }
+    if metrics.read_only > 0 {
+        say!("{} file(s) would be patched, but are on a read-only filesystem", metrics.read_only);
+    }
+    if metrics.denied > 0 {
+        say!("{} path(s) skipped: permission denied (--strict aborts instead)", metrics.denied);
+    }
+
+    if metrics.interrupted {
+        // the journal is kept so the run can be resumed
+        let signal = received_signal.load(Ordering::Relaxed) as i32;
+        eprintln!("interrupted by signal {}, stopped after the file in progress", signal);
+        flush_stable_output();
+        std::process::exit(128 + signal);
+    }
+
+    // a completed run has nothing left to resume
+    if let Some(journal_path) = journal_path {
+        Journal::remove(journal_path)?;
+    }
+
+    if metrics.errors > 0 {
+        let code = if unresolved == metrics.errors { ExitCode::UnresolvedInterpreter } else { ExitCode::Failed };
+        bail!(Failure(code, format!("{} file(s) could not be patched", metrics.errors)));
+    }
+    if metrics.unstable > 0 {
+        bail!(Failure(ExitCode::NotIdempotent, format!("{} file(s) have a shebang rewrite that is not idempotent", metrics.unstable)));
+    }
+    let hook_failures = printer.hook_failures.load(Ordering::Relaxed);
+    if hook_failures > 0 {
+        bail!(Failure(ExitCode::HookFailed, format!("post-hook failed for {} file(s)", hook_failures)));
+    }
+
+    Ok(())
+}
+
+fn exit_codes_help() -> String {
+    let mut help = "Exit codes:".to_string();
+    for code in ExitCode::ALL {
+        help += &format!("\n  {}  {}", code.code(), code.description());
+    }
+    help + "\n  128+N  stopped by signal N"
+}
+
+fn cli() -> Command {
+    Command::new("patchShebangs")
         .about("Patches script interpreter paths")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(concat!(
+            env!("CARGO_PKG_VERSION"),
+            "\ncommit: ", env!("PATCHSHEBANGS_GIT_COMMIT"),
+            "\nbuilt: ", env!("PATCHSHEBANGS_BUILD_DATE"),
+            "\nfeatures: ", env!("PATCHSHEBANGS_FEATURES"),
+            "\ntarget: ", env!("PATCHSHEBANGS_TARGET"),
+        ))
+        .after_long_help(exit_codes_help())
         .arg(Arg::new("host").long("host").action(clap::ArgAction::SetTrue))
         .arg(Arg::new("build").long("build").action(clap::ArgAction::SetTrue))
         .arg(Arg::new("update").long("update").action(clap::ArgAction::SetTrue))
-        .arg(Arg::new("paths").num_args(1..).required(true))
-        .get_matches();
+        .arg(Arg::new("path").long("path").value_name("DIRS")
+            .help("Colon-separated directories to search for interpreters instead of $PATH (or $HOST_PATH with --host)"))
+        .arg(Arg::new("path-file").long("path-file").value_name("FILE")
+            .conflicts_with("path")
+            .help("Like --path, but read the directories from FILE (one per line or colon-separated), e.g. when another tool computes a path too long for the command line"))
+        .arg(Arg::new("prepend-path").long("prepend-path").value_name("DIRS")
+            .action(clap::ArgAction::Append)
+            .help("Search these colon-separated directories before the --path/$PATH ones, e.g. the bin dirs of build inputs. Repeatable, earlier ones first"))
+        .arg(Arg::new("append-path").long("append-path").value_name("DIRS")
+            .action(clap::ArgAction::Append)
+            .help("Search these colon-separated directories after the --path/$PATH ones. Repeatable, earlier ones first"))
+        .arg(Arg::new("paths-for").long("paths-for").value_name("ROOT=DIRS")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_root_path)
+            .help("Search DIRS instead of --path/$PATH when patching the input path ROOT, so one run can patch several outputs against their own interpreters. Repeatable"))
+        .arg(Arg::new("interpreter-dir").long("interpreter-dir").value_name("DIR")
+            .action(clap::ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .conflicts_with_all(["path", "path-file", "paths-for", "prepend-path", "append-path"])
+            .help("Only search DIR for interpreters, ignoring $PATH and $HOST_PATH entirely. Repeatable, searched in the order given"))
+        .arg(Arg::new("verify-idempotent").long("verify-idempotent").action(clap::ArgAction::SetTrue)
+            .help("After patching, check that re-running would not change the shebang again"))
+        .arg(Arg::new("resolve").long("resolve").value_name("STRATEGY")
+            .value_parser(clap::value_parser!(ResolveStrategy))
+            .default_value("first")
+            .help("Which match wins when an interpreter is found in multiple PATH entries"))
+        .arg(Arg::new("require").long("require").value_name("CONSTRAINT")
+            .action(clap::ArgAction::Append)
+            .value_parser(VersionRequirement::parse)
+            .help("Only accept interpreters whose --version satisfies e.g. 'python3>=3.10' (repeatable)"))
+        .arg(Arg::new("canonicalize").long("canonicalize").action(clap::ArgAction::SetTrue)
+            .conflicts_with("keep-symlink-path")
+            .help("Resolve symlinks in the found interpreter path (e.g. a profile link to its /nix/store target). Beware of multi-call binaries like coreutils, which depend on the name they are invoked by"))
+        .arg(Arg::new("keep-symlink-path").long("keep-symlink-path").action(clap::ArgAction::SetTrue)
+            .help("Write the interpreter path exactly as found on PATH, even if it is a symlink (default)"))
+        .arg(Arg::new("busybox").long("busybox").action(clap::ArgAction::SetTrue)
+            .help("When busybox is the only provider of an interpreter (or a canonicalized interpreter is busybox), write `#!/path/to/busybox APPLET`. Shebangs passing further arguments need env -S then"))
+        .arg(Arg::new("spaces").long("spaces").value_name("POLICY")
+            .value_parser(clap::value_parser!(SpacesPolicy))
+            .default_value("error")
+            .help("What to do when an interpreter path contains whitespace (e.g. in a custom store): error, or env-split to run it through `env -S` with the path quoted"))
+        .arg(Arg::new("nix-shell").long("nix-shell").value_name("POLICY")
+            .value_parser(clap::value_parser!(NixShellPolicy))
+            .default_value("interpreter")
+            .help("What to do with the `#! nix-shell -i INTERPRETER -p ...` lines after a `#!/usr/bin/env nix-shell` shebang: interpreter patches the -i interpreter as well, first-line leaves them alone, and direct points the shebang straight at the -i interpreter (keeping the lines as comments)"))
+        .arg(Arg::new("suggest-packages").long("suggest-packages").action(clap::ArgAction::SetTrue)
+            .help("When an interpreter can't be found, ask nix-locate which packages provide it"))
+        .arg(Arg::new("resolver").long("resolver").value_name("CMD")
+            .help("Shell command that gets the interpreter name as its argument (and on stdin) and prints the absolute path to use; printing nothing falls back to the PATH search"))
+        .arg(Arg::new("fhs").long("fhs").action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["resolver", "pre-hook", "paths-for"])
+            .help("Point shebangs at the standard FHS locations (/bin/bash, /usr/bin/env, /usr/bin/python3, ...) instead of searching PATH, for trees headed to a conventional distro or an FHS chroot. Add --update to also rewrite shebangs into /nix/store"))
+        .arg(Arg::new("post-hook").long("post-hook").value_name("CMD")
+            .help("Shell command run with the path of each patched file as its argument (e.g. to re-sign or re-hash it)"))
+        .arg(Arg::new("pre-hook").long("pre-hook").value_name("CMD")
+            .help("Shell command run with each root path before it is processed. Its output lines are either NAME=/abs/path interpreter mappings or directories to put in front of the search path"))
+        .arg(Arg::new("manifest").long("manifest").value_name("FILE")
+            .help("Write a JSON record of every change (paths, old/new shebangs, sha256 before/after, timestamps)"))
+        .arg(Arg::new("emit-patch").long("emit-patch").value_name("FILE")
+            .help("Write every shebang change as a patch (relative to the working directory), to review or to apply elsewhere with git apply"))
+        .arg(Arg::new("sbom").long("sbom").value_name("FILE")
+            .help("Write a CycloneDX SBOM of the interpreters the patched scripts now depend on"))
+        .arg(Arg::new("record").long("record").value_name("FILE")
+            .conflicts_with_all(["replay", "paths-for", "pre-hook"])
+            .help("Write every interpreter lookup and the path it resolved to into FILE, for --replay"))
+        .arg(Arg::new("replay").long("replay").value_name("FILE")
+            .conflicts_with_all(["paths-for", "pre-hook"])
+            .help("Resolve interpreters only from the lookups a --record run wrote to FILE (failing on any other), so re-runs produce the same output whatever is on PATH now"))
+        .arg(Arg::new("closure").long("closure").value_name("PATH|FILE")
+            .help("Fail files whose interpreter is outside the runtime closure of PATH (a store path or a link to one, queried with nix-store -q --requisites), or of the store paths listed in FILE"))
+        .arg(Arg::new("closure-warn").long("closure-warn").action(clap::ArgAction::SetTrue).requires("closure")
+            .help("Only warn about interpreters outside the --closure, and patch them in anyway"))
+        .arg(Arg::new("trusted-prefix").long("trusted-prefix").value_name("PREFIX").action(clap::ArgAction::Append)
+            .help("Warn about interpreters that are under none of these prefixes and none of the build's inputs ($buildInputs, $nativeBuildInputs, ...) or outputs. Inside a nix build, the inputs are checked even without this"))
+        .arg(Arg::new("gc-root").long("gc-root").value_name("DIR")
+            .help("Keep every store path an interpreter was resolved into alive, with indirect GC roots (nix-store --add-root) in DIR. For trees patched outside a derivation, which nothing else roots"))
+        .arg(Arg::new("vendor-into").long("vendor-into").value_name("DIR")
+            .help("Copy every resolved interpreter into DIR (relative to each input path) and point the shebangs at the copies, so the tree also runs where the store paths don't exist"))
+        .arg(Arg::new("vendor-symlink").long("vendor-symlink").action(clap::ArgAction::SetTrue).requires("vendor-into")
+            .help("Symlink the interpreters into the --vendor-into directory instead of copying them"))
+        .arg(Arg::new("provenance").long("provenance").value_name("FILE")
+            .help("Write an in-toto statement with SLSA provenance describing this run"))
+        .arg(Arg::new("metrics-file").long("metrics-file").value_name("FILE")
+            .help("Write the run's counts (files scanned, patched, errors, ...) and duration to FILE in the Prometheus text format, e.g. for node_exporter's textfile collector"))
+        .arg(Arg::new("hash").long("hash").action(clap::ArgAction::SetTrue)
+            .help("Print the sha256 of each patched file before and after patching, and of the unchanged body after the shebang line"))
+        .arg(Arg::new("no-lock").long("no-lock").action(clap::ArgAction::SetTrue)
+            .help("Don't take the advisory lock that keeps concurrent runs from patching the same root"))
+        .arg(Arg::new("journal").long("journal").value_name("FILE")
+            .help("Append every finished file to FILE as it happens, so an interrupted run can be resumed"))
+        .arg(Arg::new("resume").long("resume").action(clap::ArgAction::SetTrue)
+            .requires("journal")
+            .help("Skip the files already listed in the --journal file from a previous, interrupted run"))
+        .arg(Arg::new("cache-file").long("cache-file").value_name("FILE")
+            .help("Remember the size and mtime of every handled file in FILE, and skip files that haven't changed since on the next run"))
+        .arg(Arg::new("newer-than").long("newer-than").value_name("TIME|FILE")
+            .value_parser(parse_newer_than)
+            .help("Only consider files modified after TIME (RFC 3339, e.g. 2024-05-01T12:00:00Z, or @SECONDS since the epoch) or after the mtime of FILE"))
+        .arg(Arg::new("git-changed").long("git-changed").value_name("REF")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("HEAD")
+            .help("Only consider files git reports as modified since REF (HEAD, i.e. uncommitted changes, by default) or untracked, for quick re-runs over a large working copy"))
+        .arg(Arg::new("git-tracked").long("git-tracked").action(clap::ArgAction::SetTrue)
+            .help("Only consider files known to git, so vendored blobs, build outputs and downloads inside a repository are never touched"))
+        .arg(Arg::new("substitute").long("substitute").value_name("@NAME@=PROGRAM")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_rule)
+            .help("Replace a placeholder interpreter, as in '#!@bash@' from a script template, with the resolved path of PROGRAM (or PROGRAM itself if it is absolute). Repeatable"))
+        .arg(Arg::new("alias").long("alias").value_name("NAME=PROGRAM")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_rule)
+            .help("Look interpreter NAME up as PROGRAM, e.g. python=python3 or node=nodejs, when the shebang name and the installed binary differ. An absolute PROGRAM is used as is. Repeatable"))
+        .arg(Arg::new("alias-file").long("alias-file").value_name("FILE")
+            .help("Read NAME=PROGRAM alias rules from FILE, one per line (# starts a comment). --alias rules win over the file's"))
+        .arg(Arg::new("replace-token").long("replace-token").value_name("TOKEN=PROGRAM")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_rule)
+            .help("Also replace TOKEN (e.g. @python3@ or __INTERPRETER__) anywhere in a script with the resolved path of PROGRAM. Repeatable"))
+        .arg(Arg::new("wrap-instead").long("wrap-instead").action(clap::ArgAction::SetTrue)
+            .conflicts_with("replace-token")
+            .help("Leave scripts byte-identical (e.g. signed ones): move each to .NAME-wrapped and generate a wrapper in its place that runs it with the resolved interpreter"))
+        .arg(Arg::new("add-missing-shebang").long("add-missing-shebang").value_name("EXT=PROGRAM")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_rule)
+            .conflicts_with("wrap-instead")
+            .help("Give executable files ending in .EXT that have no shebang one for PROGRAM, e.g. sh=bash or py=python3. Repeatable"))
+        .arg(Arg::new("compat-macos").long("compat-macos").action(clap::ArgAction::SetTrue)
+            .help("Rewrite `env -S PROG ARG` shebangs to run PROG directly, so trees shared with older macOS releases (whose env lacks -S) keep working"))
+        .arg(Arg::new("env-dialect").long("env-dialect").value_name("DIALECT")
+            .value_parser(clap::value_parser!(EnvDialect))
+            .default_value("gnu")
+            .help("Which env reads the shebangs: gnu, or bsd to understand FreeBSD/NetBSD option clustering (-iS) and -P altpath"))
+        .arg(Arg::new("strict").long("strict").action(clap::ArgAction::SetTrue)
+            .help("Abort on files and directories that can't be read or replaced (EACCES/EPERM) instead of skipping them with a warning"))
+        .arg(Arg::new("continue-read-only").long("continue-read-only").action(clap::ArgAction::SetTrue)
+            .help("List scripts on a read-only filesystem (e.g. a mounted /nix/store) that would be patched and keep going, instead of stopping at the first one"))
+        .arg(Arg::new("fsync").long("fsync").action(clap::ArgAction::SetTrue)
+            .help("fsync each rewritten file and its directory, for pipelines that snapshot the tree right afterwards"))
+        .arg(Arg::new("no-preserve-mtime").long("no-preserve-mtime").action(clap::ArgAction::SetTrue)
+            .help("Give patched files the time of the rewrite as their mtime, instead of keeping the original one"))
+        .arg(Arg::new("set-mtime").long("set-mtime").value_name("EPOCH")
+            .value_parser(clap::value_parser!(u64))
+            .conflicts_with("no-preserve-mtime")
+            .help("Give every patched file this mtime, in seconds since the epoch, e.g. $SOURCE_DATE_EPOCH for reproducible archives"))
+        .arg(Arg::new("io-nice").long("io-nice").value_name("BYTES_PER_SEC")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("8M")
+            .value_parser(parse_rate)
+            .help("Run at the lowest CPU and I/O priority and read/write at most BYTES_PER_SEC (K/M/G suffixes, default 8M), for background runs on shared builders"))
+        .arg(Arg::new("archive").long("archive").action(clap::ArgAction::SetTrue)
+            .help("Treat .tar, .tar.gz, .tar.xz, .tar.zst, .zip and .whl files given as paths as archives, and patch the scripts inside them"))
+        .arg(Arg::new("max-size").long("max-size").value_name("BYTES")
+            .value_parser(parse_rate)
+            .help("Skip files larger than BYTES (K/M/G suffixes), e.g. to step around huge generated files that can't be scripts"))
+        .arg(Arg::new("jobs").long("jobs").short('j').value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("1")
+            .help("Patch N files at once (0 uses one thread per CPU). The output is the same as one file at a time"))
+        .arg(Arg::new("queue-depth").long("queue-depth").value_name("[STAGE=]N")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_queue_depth)
+            .help("With --jobs, how many files may wait to be classified, rewritten or reported (STAGE is classify, rewrite or report; all three without one). Defaults to twice --jobs"))
+        .arg(Arg::new("stable-output").long("stable-output").action(clap::ArgAction::SetTrue)
+            .help("Print the output sorted, with paths relative to the working directory and without timings, for golden-file and snapshot tests"))
+        .arg(Arg::new("timings").long("timings").action(clap::ArgAction::SetTrue)
+            .help("At the end, print the time spent walking, classifying files, resolving interpreters and writing, per thread, e.g. to tune --jobs or spot a slow filesystem"))
+        .arg(Arg::new("verbose").long("verbose").short('v').action(clap::ArgAction::Count)
+            .help("Also print every skipped file, with a stable reason code (no-shebang, binary, store-path, up-to-date, wrapped-original, malformed, not-executable, excluded, too-large). Given twice, also trace every PATH directory probed on stderr, and why candidates were rejected. NIX_DEBUG=1 implies -v, NIX_DEBUG=3 and up -vv"))
+        .arg(Arg::new("group-by-dir").long("group-by-dir").action(clap::ArgAction::SetTrue)
+            .help("Instead of a line per patched file, print how many were patched in each directory at the end"))
+        .arg(Arg::new("ignore-missing").long("ignore-missing").action(clap::ArgAction::SetTrue)
+            .help("Skip input paths that do not exist (with a warning) instead of failing before anything is patched"))
+        .arg(Arg::new("skip-env").long("skip-env").value_name("VAR")
+            .default_value("dontPatchShebangs")
+            .help("Patch nothing when the environment variable VAR is set (to anything but the empty string)"))
+        .arg(Arg::new("skip-for-env").long("skip-for-env").value_name("VAR")
+            .default_value("dontPatchShebangsFor")
+            .help("Leave the subtrees listed in the environment variable VAR alone, separated by whitespace. Relative ones are relative to each input path"))
+        .arg(Arg::new("all-outputs").long("all-outputs").action(clap::ArgAction::SetTrue)
+            .help("Also patch every output of the derivation being built: each name in $outputs (e.g. out dev lib) is looked up as an environment variable"))
+        .arg(Arg::new("paths").num_args(1..)
+            .help("Directories, files (and with --archive, archives) to patch. Defaults to $out, as in a Nix builder"))
+        .subcommand(Command::new("compare")
+            .about("Report the shebang differences between two trees")
+            .arg(Arg::new("a").value_name("TREE_A").required(true))
+            .arg(Arg::new("b").value_name("TREE_B").required(true)))
+        .subcommand(Command::new("apply")
+            .about("Make the shebang changes recorded in a --manifest file again, e.g. on another machine. Files whose first line is no longer the old shebang are left alone")
+            .arg(Arg::new("dir").long("dir").value_name("DIR")
+                .default_value(".")
+                .help("Where the relative paths in the manifest start"))
+            .arg(Arg::new("fsync").long("fsync").action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("manifest").value_name("MANIFEST").required(true)))
+        .subcommand(Command::new("audit")
+            .about("Classify every shebang (store-absolute, non-store-absolute, env-style, relative, broken) without changing anything")
+            .arg(Arg::new("env-dialect").long("env-dialect").value_name("DIALECT")
+                .value_parser(clap::value_parser!(EnvDialect))
+                .default_value("gnu"))
+            .arg(Arg::new("paths").num_args(1..).required(true)))
+        .subcommand(Command::new("explain")
+            .about("Walk through what patching would do with FILE and why: the shebang, how it parses, every PATH candidate checked and the final line. Changes nothing")
+            .arg(Arg::new("host").long("host").action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("path").long("path").value_name("DIRS")
+                .help("Search path for interpreters (defaults to $PATH, or $HOST_PATH with --host)"))
+            .arg(Arg::new("update").long("update").action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("env-dialect").long("env-dialect").value_name("DIALECT")
+                .value_parser(clap::value_parser!(EnvDialect))
+                .default_value("gnu"))
+            .arg(Arg::new("file").value_name("FILE").required(true)))
+        .subcommand(Command::new("lint")
+            .about("Check shebangs for common mistakes, each reported with a rule ID (SB001 bashism in an sh script, SB002 env with several arguments but no -S, SB003 interpreter not on the search path, SB004 line too long for older kernels). Fails on errors, not warnings")
+            .arg(Arg::new("env-dialect").long("env-dialect").value_name("DIALECT")
+                .value_parser(clap::value_parser!(EnvDialect))
+                .default_value("gnu"))
+            .arg(Arg::new("path").long("path").value_name("PATH")
+                .help("Search path the interpreters have to be on (defaults to $PATH)"))
+            .arg(Arg::new("paths").num_args(1..).required(true)))
+        .subcommand(Command::new("bench")
+            .about("Measure walk/parse/rewrite throughput on a synthesized tree, with and without parallelism")
+            .arg(Arg::new("files").long("files").value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000"))
+            .arg(Arg::new("jobs").long("jobs").short('j').value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("0")
+                .help("Workers for the parallel runs (0 uses one per CPU)"))
+            .arg(Arg::new("dir").long("dir").value_name("DIR")
+                .help("Where to create the scratch tree (defaults to the system temp directory)")))
+        .subcommand(Command::new("oci")
+            .about("Patch the scripts inside the layers of an OCI image layout or docker-archive, recomputing all digests")
+            .arg(Arg::new("image").value_name("IMAGE").required(true)
+                .help("An OCI image layout directory, or a tarball written by docker save"))
+            .arg(Arg::new("path").long("path").value_name("PATH")
+                .help("Search path for interpreters, as they will be found inside the image (defaults to $PATH)"))
+            .arg(Arg::new("map").long("map").value_name("NAME=PATH")
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_rule)
+                .help("Use PATH for interpreter NAME without searching. Repeatable"))
+            .arg(Arg::new("update").long("update").action(clap::ArgAction::SetTrue)))
+        .subcommand(Command::new("daemon")
+            .about("Answer patch, check and resolve requests (JSON-RPC 2.0, one per line) on a Unix socket, sharing one interpreter lookup cache between them, until a shutdown request")
+            .arg(Arg::new("socket").long("socket").value_name("FILE").required(true)
+                .help("Where to listen. A socket left behind by an earlier daemon is replaced"))
+            .arg(Arg::new("path").long("path").value_name("DIRS")
+                .help("Search path for requests that don't bring their own (defaults to $PATH)")))
+        .subcommand(Command::new("completions")
+            .about("Print a shell completion script")
+            .arg(Arg::new("shell").value_parser(clap::value_parser!(Shell)).required(true)))
+        .subcommand(Command::new("mangen")
+            .about("Print a roff man page generated from this CLI definition")
+            .hide(true))
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+}
 
-    let update = matches.get_flag("update");
-    let use_host_path = matches.get_flag("host");
+fn audit_command(matches: &ArgMatches) -> Result<()> {
+    let dialect = *matches.get_one::<EnvDialect>("env-dialect").unwrap();
+    let mut counts = HashMap::new();
+    for root in matches.get_many::<String>("paths").unwrap() {
+        for entry in audit_tree(Path::new(root), dialect)? {
+            println!("{:<18} {}: {}", entry.class.name(), entry.path.display(), entry.shebang);
+            *counts.entry(entry.class).or_insert(0) += 1;
+        }
+    }
+    println!();
+    for class in ShebangClass::ALL {
+        println!("{:<18} {}", class.name(), counts.get(&class).unwrap_or(&0));
+    }
+    Ok(())
+}
 
-    let path_env = if use_host_path {
-        env::var("HOST_PATH").unwrap_or_default()
-    } else {
-        env::var("PATH").unwrap_or_default()
+fn explain_command(matches: &ArgMatches) -> Result<()> {
+    let search_path = match matches.get_one::<String>("path") {
+        Some(path) => path.clone(),
+        None if matches.get_flag("host") => env::var("HOST_PATH").unwrap_or_default(),
+        None => env::var("PATH").unwrap_or_default(),
     };
+    let mut resolver = PathResolver::new(search_path);
+    resolver.trace = Some(Box::new(|line: &str| println!("  {}", line)));
+    let options = Options {
+        update: matches.get_flag("update"),
+        env_dialect: *matches.get_one::<EnvDialect>("env-dialect").unwrap(),
+        ..Options::default()
+    };
+    let file = Path::new(matches.get_one::<String>("file").unwrap());
+    explain_file(file, &options, &resolver, &mut |step| println!("{}", step))?;
+    Ok(())
+}
 
-    let paths: Vec<&String> = matches.get_many::<String>("paths").unwrap().collect();
-    println!("Patching script interpreter paths in {:?}", paths);
+fn lint_command(matches: &ArgMatches) -> Result<()> {
+    let dialect = *matches.get_one::<EnvDialect>("env-dialect").unwrap();
+    let search_path = matches.get_one::<String>("path").cloned().unwrap_or_else(|| env::var("PATH").unwrap_or_default());
+    let resolver = PathResolver::new(search_path);
+    let mut errors = 0;
+    for root in matches.get_many::<String>("paths").unwrap() {
+        for finding in lint_tree(Path::new(root), dialect, &resolver)? {
+            let severity = match finding.rule.severity() {
+                Severity::Warning => "warning",
+                Severity::Error => {
+                    errors += 1;
+                    "error"
+                }
+            };
+            println!("{}: {} {}: {}", finding.path.display(), finding.rule.id(), severity, finding.message);
+        }
+    }
+    if errors > 0 {
+        bail!(Failure(ExitCode::ChangesNeeded, format!("{} lint error(s)", errors)));
+    }
+    Ok(())
+}
 
-    for path in paths {
-        patch_shebangs_in_path(path, &path_env, update)?;
+fn compare_command(matches: &ArgMatches) -> Result<()> {
+    let a = matches.get_one::<String>("a").unwrap();
+    let b = matches.get_one::<String>("b").unwrap();
+    let differences = compare_trees(Path::new(a), Path::new(b))?;
+    for difference in &differences {
+        match difference {
+            ShebangDifference::OnlyInA { path, shebang } => println!("only in {}: {}: {}", a, path.display(), shebang),
+            ShebangDifference::OnlyInB { path, shebang } => println!("only in {}: {}: {}", b, path.display(), shebang),
+            ShebangDifference::Changed { path, a: a_shebang, b: b_shebang } => {
+                println!("{}:\n    - {}\n    + {}", path.display(), a_shebang, b_shebang)
+            }
+        }
+    }
+    if !differences.is_empty() {
+        bail!(Failure(ExitCode::ChangesNeeded, format!("{} shebang difference(s)", differences.len())));
     }
+    Ok(())
+}
 
+fn apply_command(matches: &ArgMatches) -> Result<()> {
+    let manifest = Manifest::load(Path::new(matches.get_one::<String>("manifest").unwrap()))?;
+    let options = Options { fsync: matches.get_flag("fsync"), ..Options::default() };
+    let mut problems = 0;
+    for (path, applied) in manifest.apply(Path::new(matches.get_one::<String>("dir").unwrap()), &options)? {
+        match applied {
+            Applied::Patched => println!("{}: applied", path.display()),
+            Applied::AlreadyDone => println!("{}: already applied", path.display()),
+            Applied::Mismatch { found } => {
+                problems += 1;
+                println!("{}: first line is {:?}, not the recorded old shebang, left alone", path.display(), found);
+            }
+            Applied::Missing => {
+                problems += 1;
+                println!("{}: missing", path.display());
+            }
+        }
+    }
+    if problems > 0 {
+        bail!("{} change(s) could not be applied", problems);
+    }
     Ok(())
 }
 
-fn patch_shebangs_in_path<P: AsRef<Path>>(path: P, path_env: &str, update: bool) -> Result<()> {
-    for entry in WalkDir::new(path) {
-        let entry = entry?;
-        let file_path = entry.path();
+fn oci_command(matches: &ArgMatches) -> Result<()> {
+    let image = matches.get_one::<String>("image").unwrap();
+    let search_path = matches.get_one::<String>("path").cloned().unwrap_or_else(|| env::var("PATH").unwrap_or_default());
+    let mappings: HashMap<_, _> = matches.get_many::<(String, String)>("map").unwrap_or_default().cloned().collect();
+    let resolver = MappingResolver::new(mappings, PathResolver::new(search_path));
+    let options = Options {
+        update: matches.get_flag("update"),
+        observer: Some(Box::new(PrintObserver { print_hashes: false, post_hook: None, hook_failures: AtomicUsize::new(0), patched_by_dir: None, verbosity: 0 })),
+        ..Options::default()
+    };
+    let report = patch_image(Path::new(image), &options, &resolver)?;
+    if !report.errors.is_empty() {
+        bail!(Failure(ExitCode::for_report(&report), format!("{} file(s) could not be patched", report.errors.len())));
+    }
+    Ok(())
+}
 
-        // Only regular executable files
-        if !entry.file_type().is_file() || entry.metadata()?.permissions().mode() & 0o100 == 0 {
-            continue;
+fn bench_command(matches: &ArgMatches) -> Result<()> {
+    let config = BenchConfig {
+        files: *matches.get_one::<usize>("files").unwrap(),
+        jobs: jobs_or_cpus(*matches.get_one::<usize>("jobs").unwrap()),
+        dir: matches.get_one::<String>("dir").map_or_else(env::temp_dir, PathBuf::from),
+    };
+    println!("{:<8} {:>5} {:>8} {:>10} {:>12}", "phase", "jobs", "files", "time", "files/s");
+    for result in run_bench(&config)? {
+        println!("{:<8} {:>5} {:>8} {:>9.3}s {:>12.0}", result.phase, result.jobs, result.files, result.elapsed.as_secs_f64(), result.files_per_second());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn daemon_command(matches: &ArgMatches) -> Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+    use patch_shebangs::daemon::Daemon;
+    let socket = Path::new(matches.get_one::<String>("socket").unwrap());
+    if fs::symlink_metadata(socket).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket).with_context(|| format!("could not listen on {}", socket.display()))?;
+    let search_path = matches.get_one::<String>("path").cloned().unwrap_or_else(|| env::var("PATH").unwrap_or_default());
+    eprintln!("listening on {}", socket.display());
+    let served = Daemon::new(search_path).serve(&listener);
+    let _ = fs::remove_file(socket);
+    Ok(served?)
+}
+
+#[cfg(not(unix))]
+fn daemon_command(_matches: &ArgMatches) -> Result<()> {
+    bail!("daemon needs Unix sockets, which this platform doesn't have")
+}
+
+fn jobs_or_cpus(jobs: usize) -> usize {
+    match jobs {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    }
+}
+
+/// The interpreter search path, from highest to lowest precedence: the `--prepend-path` dirs (in the order
+/// given), then `--path` (or the `--path-file` contents) or else $HOST_PATH (with --host) or $PATH, then the `--append-path` dirs.
+/// `--interpreter-dir` replaces all of them, so nothing from the environment is searched.
+/// A `--paths-for` override takes the place of the `--path`/$PATH layer
+fn search_path(matches: &ArgMatches, root_override: Option<&str>) -> Result<String> {
+    if let Some(dirs) = matches.get_many::<PathBuf>("interpreter-dir") {
+        return Ok(env::join_paths(dirs)?.to_string_lossy().to_string());
+    }
+    let base = match root_override.or(matches.get_one::<String>("path").map(String::as_str)) {
+        Some(path) => path.to_string(),
+        None if let Some(path_file) = matches.get_one::<String>("path-file") => read_path_file(Path::new(path_file))?,
+        None if matches.get_flag("host") => env::var("HOST_PATH").unwrap_or_default(),
+        None => env::var("PATH").unwrap_or_default(),
+    };
+    let layer = |id| matches.get_many::<String>(id).unwrap_or_default().flat_map(env::split_paths).collect::<Vec<_>>();
+    let mut dirs = layer("prepend-path");
+    dirs.extend(env::split_paths(&base));
+    dirs.extend(layer("append-path"));
+    Ok(env::join_paths(dirs)?.to_string_lossy().to_string())
+}
+
+/// The `--paths-for ROOT=PATH` overrides by canonical root; a ROOT that isn't one of the inputs is most likely a typo
+fn root_search_paths(matches: &ArgMatches, inputs: &[PathBuf]) -> HashMap<PathBuf, String> {
+    let mut overrides = HashMap::new();
+    for (root, path) in matches.get_many::<(String, String)>("paths-for").unwrap_or_default() {
+        let canonical = fs::canonicalize(root).ok().filter(|canonical| inputs.contains(canonical));
+        match canonical {
+            Some(canonical) => { overrides.insert(canonical, path.clone()); }
+            None => eprintln!("warning: --paths-for {} isn't one of the input paths, ignoring it", root),
         }
+    }
+    overrides
+}
 
-        if let Some(new_interpreter) = process_file(file_path, path_env, update)? {
-            println!("{}: shebang updated to {}", file_path.display(), new_interpreter);
+/// The `--alias-file` rules, overridden by the `--alias` ones
+fn interpreter_aliases(matches: &ArgMatches) -> Result<HashMap<String, String>> {
+    let mut aliases = HashMap::new();
+    if let Some(alias_file) = matches.get_one::<String>("alias-file") {
+        let contents = fs::read_to_string(alias_file).with_context(|| format!("can't read --alias-file {}", alias_file))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_rule(line) {
+                Ok((name, program)) => { aliases.insert(name, program); }
+                Err(error) => bail!("{}:{}: {}", alias_file, number + 1, error),
+            }
         }
     }
-    Ok(())
+    aliases.extend(matches.get_many::<(String, String)>("alias").unwrap_or_default().cloned());
+    Ok(aliases)
 }
 
-fn process_file(path: &Path, path_env: &str, update: bool) -> Result<Option<String>> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut first_line = String::new();
+/// One directory per line, or colon-separated (or both); blank lines are ignored
+fn read_path_file(path_file: &Path) -> Result<String> {
+    let contents = fs::read_to_string(path_file).with_context(|| format!("can't read --path-file {}", path_file.display()))?;
+    let dirs = contents.lines().map(str::trim).filter(|line| !line.is_empty()).flat_map(env::split_paths);
+    Ok(env::join_paths(dirs)?.to_string_lossy().to_string())
+}
 
-    let bytes_read = reader.read_line(&mut first_line)?;
-    if bytes_read == 0 || !first_line.starts_with("#!") {
-        return Ok(None); // not a shebang script
+/// The paths given, or else `$out`, like the patchShebangs hook in a Nix builder.
+/// With --all-outputs, every output named in `$outputs` is added, the way fixupPhase goes through them
+fn input_paths(matches: &ArgMatches) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = matches.get_many::<String>("paths").unwrap_or_default().cloned().collect();
+    if matches.get_flag("all-outputs") {
+        // a derivation without `outputs` only has out
+        let outputs = env::var("outputs").unwrap_or_else(|_| "out".to_string());
+        for output in outputs.split_whitespace() {
+            match env::var(output) {
+                // outputs that never got created are nothing to patch, not an error
+                Ok(path) if Path::new(&path).exists() => paths.push(path),
+                Ok(path) if !path.is_empty() => eprintln!("warning: output {} ({}) does not exist, skipping it", output, path),
+                _ => eprintln!("warning: ${} is not set, skipping output {}", output, output),
+            }
+        }
     }
+    if !paths.is_empty() {
+        return Ok(paths);
+    }
+    match env::var("out") {
+        Ok(out) if !out.is_empty() => {
+            eprintln!("warning: no paths given, patching $out ({})", out);
+            Ok(vec![out])
+        }
+        _ => bail!(Failure(ExitCode::Usage, "no paths given (and $out, which a Nix builder sets, is not set either)".to_string())),
+    }
+}
 
-    let original_shebang = first_line.trim_end().to_string();
-    let shebang_content = original_shebang.trim_start_matches("#!").trim();
-
-    let mut parts = shebang_content.split_whitespace();
-    let interpreter = parts.next().unwrap_or("");
-    let mut args: Vec<&str> = parts.collect();
+/// Checks every input before anything is patched, instead of the walk failing on them partway through
+fn existing_inputs(paths: Vec<&String>, ignore_missing: bool) -> Result<Vec<&String>> {
+    let (existing, missing): (Vec<_>, Vec<_>) = paths.into_iter().partition(|path| fs::metadata(path).is_ok());
+    if !missing.is_empty() {
+        let missing = missing.iter().map(|path| path.as_str()).collect::<Vec<_>>().join(", ");
+        if !ignore_missing {
+            bail!("input path(s) do not exist: {} (--ignore-missing skips them)", missing);
+        }
+        eprintln!("warning: skipping input path(s) that do not exist: {}", missing);
+    }
+    Ok(existing)
+}
 
-    let new_interpreter_line = if interpreter.ends_with("/env") {
-        // Handle env shebang
-        if let Some(first_arg) = args.first() {
-            if *first_arg == "-S" {
-                args.remove(0);
-                if args.is_empty() {
-                    bail!("Invalid -S usage in shebang: {}", original_shebang);
-                }
-                let prog = args.remove(0);
-                let prog_path = which_in_path(prog, path_env)?;
-                let env_path = which_in_path("env", path_env)?;
-                format!("#!{} -S {} {}", env_path, prog_path, args.join(" "))
-            } else if first_arg.starts_with('-') || first_arg.contains('=') {
-                bail!("Unsupported env usage in shebang: {}", original_shebang);
+/// Drops inputs that are the same as an earlier one, or inside another one, so no file is patched
+/// (or counted) twice. Archives inside a directory input are kept when `is_archive` says they will be
+/// opened, since walking the directory doesn't look into them
+fn dedupe_inputs(paths: Vec<&String>, is_archive: impl Fn(&Path) -> bool) -> Vec<&String> {
+    let canonical: Vec<Option<PathBuf>> = paths.iter().map(|path| fs::canonicalize(path).ok()).collect();
+    let mut kept = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let Some(own) = &canonical[i] else {
+            kept.push(*path);
+            continue;
+        };
+        let covered_by = canonical.iter().enumerate().find_map(|(j, other)| {
+            let other = other.as_ref()?;
+            if j < i && own == other {
+                Some(format!("{} is the same as {}", path, paths[j]))
+            } else if own != other && own.starts_with(other) && !is_archive(own) {
+                Some(format!("{} is inside {}", path, paths[j]))
             } else {
-                let prog_path = which_in_path(first_arg, path_env)?;
-                format!("#!{}", prog_path)
+                None
             }
-        } else {
-            bail!("Invalid env usage in shebang: {}", original_shebang);
+        });
+        match covered_by {
+            Some(reason) => eprintln!("warning: {}, skipping it", reason),
+            None => kept.push(*path),
         }
-    } else {
-        // Regular interpreter
-        let base = Path::new(interpreter)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or(interpreter);
+    }
+    kept
+}
+
+/// A byte count with an optional K, M or G (binary) suffix
+fn parse_rate(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&value[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&value[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number.saturating_mul(multiplier)),
+        _ => Err(format!("expected a positive number of bytes, optionally with a K/M/G suffix, got {}", value)),
+    }
+}
+
+/// Nice 19 and, on Linux, the idle I/O scheduling class; inherited by the worker threads spawned later
+#[cfg(unix)]
+fn lower_priority() {
+    // SAFETY: plain syscalls on the calling process, failures only mean we keep our priority
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+        #[cfg(target_os = "linux")]
+        {
+            const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+            const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << 13);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_priority() {}
+
+/// `--newer-than` takes either a point in time or a reference file whose mtime is used
+fn parse_newer_than(value: &str) -> Result<SystemTime, String> {
+    if let Some(seconds) = value.strip_prefix('@') {
+        let seconds: u64 = seconds.parse().map_err(|_| format!("invalid epoch seconds: {}", seconds))?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+    if let Ok(metadata) = fs::metadata(value) {
+        return metadata.modified().map_err(|e| format!("{}: {}", value, e));
+    }
+    humantime::parse_rfc3339_weak(value)
+        .map_err(|_| format!("{} is neither an existing file nor an RFC 3339 time (e.g. 2024-05-01T12:00:00Z)", value))
+}
 
-        let resolved = which_in_path(base, path_env)?;
-        let all_args = std::iter::once(resolved.as_str()).chain(args.iter().copied()).collect::<Vec<_>>();
-        format!("#!{}", all_args.join(" "))
+fn parse_root_path(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((root, path)) if !root.is_empty() => Ok((root.to_string(), path.to_string())),
+        _ => Err(format!("expected ROOT=DIRS, got {}", value)),
+    }
+}
+
+fn parse_queue_depth(value: &str) -> Result<(Option<String>, usize), String> {
+    let (stage, depth) = match value.split_once('=') {
+        Some((stage, depth)) if ["classify", "rewrite", "report"].contains(&stage) => (Some(stage.to_string()), depth),
+        Some((stage, _)) => return Err(format!("unknown stage {} (expected classify, rewrite or report)", stage)),
+        None => (None, value),
+    };
+    let depth = depth.parse().map_err(|_| format!("expected a number of files, got {}", depth))?;
+    Ok((stage, depth))
+}
+
+fn queue_depths(matches: &ArgMatches) -> QueueDepths {
+    let mut depths = QueueDepths::default();
+    for (stage, depth) in matches.get_many::<(Option<String>, usize)>("queue-depth").unwrap_or_default() {
+        match stage.as_deref() {
+            Some("classify") => depths.classify = Some(*depth),
+            Some("rewrite") => depths.rewrite = Some(*depth),
+            Some(_) => depths.report = Some(*depth),
+            None => depths = QueueDepths { classify: Some(*depth), rewrite: Some(*depth), report: Some(*depth) },
+        }
+    }
+    depths
+}
+
+fn parse_rule(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, target)) if !name.is_empty() && !target.is_empty() => Ok((name.to_string(), target.to_string())),
+        _ => Err(format!("expected NAME=PROGRAM, got {}", value)),
+    }
+}
+
+/// Everything that affects what a file gets patched to; a cache written with other settings is discarded
+fn cache_settings(matches: &ArgMatches, path_env: &str, aliases: &HashMap<String, String>) -> String {
+    let mut settings = vec![path_env.to_string(), matches.get_flag("update").to_string()];
+    let mut aliases: Vec<_> = aliases.iter().map(|(name, program)| format!("{}={}", name, program)).collect();
+    aliases.sort();
+    settings.extend(aliases);
+    for id in ["paths-for", "spaces", "nix-shell", "add-missing-shebang", "resolve", "require", "resolver", "pre-hook", "substitute", "replace-token", "env-dialect"] {
+        settings.extend(matches.get_raw(id).unwrap_or_default().map(|value| value.to_string_lossy().to_string()));
+    }
+    settings.push(matches.get_flag("canonicalize").to_string());
+    settings.push(matches.get_flag("wrap-instead").to_string());
+    settings.push(matches.get_flag("compat-macos").to_string());
+    settings.push(matches.get_flag("busybox").to_string());
+    settings.push(matches.get_flag("fhs").to_string());
+    settings.join("\n")
+}
+
+/// How many times -v was given, raised by `NIX_DEBUG` the way nixpkgs' shell hooks get noisier: from 1 on,
+/// every skipped file is listed, and from 3 on, every PATH lookup is traced too
+fn verbosity(matches: &ArgMatches) -> u8 {
+    let nix_debug = match env::var("NIX_DEBUG").ok().and_then(|level| level.parse::<u32>().ok()) {
+        Some(3..) => 2,
+        Some(1..) => 1,
+        _ => 0,
     };
+    matches.get_count("verbose").max(nix_debug)
+}
 
-    if original_shebang != new_interpreter_line {
-        if update || !interpreter.starts_with("/nix/store") {
-            // Read full content
-            let content = fs::read_to_string(path)?;
-            let updated = content.replacen(&original_shebang, &new_interpreter_line, 1);
+/// What `--trusted-prefix` checks interpreters against: the prefixes given, plus the inputs and outputs
+/// of the nix build this runs in. None when there is neither
+fn trusted_prefixes(matches: &ArgMatches) -> Option<Vec<PathBuf>> {
+    let mut prefixes: Vec<PathBuf> = matches.get_many::<String>("trusted-prefix").unwrap_or_default().map(PathBuf::from).collect();
+    if prefixes.is_empty() && env::var_os("NIX_BUILD_TOP").is_none() {
+        return None;
+    }
+    for variable in INPUT_VARIABLES {
+        prefixes.extend(env::var(variable).unwrap_or_default().split_whitespace().map(PathBuf::from));
+    }
+    let outputs = env::var("outputs").unwrap_or_else(|_| "out".to_string());
+    prefixes.extend(outputs.split_whitespace().filter_map(env::var_os).map(PathBuf::from));
+    Some(prefixes)
+}
 
-            // Preserve timestamp
-            let metadata = fs::metadata(path)?;
-            let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+/// The stdenv variables listing a build's dependencies
+const INPUT_VARIABLES: [&str; 10] = [
+    "buildInputs", "nativeBuildInputs", "propagatedBuildInputs", "propagatedNativeBuildInputs",
+    "depsBuildBuild", "depsBuildTarget", "depsHostHost", "depsTargetTarget", "depsBuildBuildPropagated", "depsHostHostPropagated",
+];
 
-            fs::write(path, updated)?;
-            filetime::set_file_mtime(path, mtime)?;
+fn build_resolver(matches: &ArgMatches, path_env: String) -> Box<dyn Resolver> {
+    if matches.get_flag("fhs") {
+        return Box::new(FhsResolver);
+    }
+    let mut path_resolver = PathResolver::new(path_env);
+    path_resolver.strategy = *matches.get_one::<ResolveStrategy>("resolve").unwrap();
+    path_resolver.requirements = matches.get_many::<VersionRequirement>("require").unwrap_or_default().cloned().collect();
+    path_resolver.symlinks = if matches.get_flag("canonicalize") { SymlinkPolicy::Canonicalize } else { SymlinkPolicy::Keep };
+    path_resolver.suggest_packages = matches.get_flag("suggest-packages");
+    if verbosity(matches) >= 2 {
+        path_resolver.trace = Some(Box::new(|line: &str| eprintln!("{}", line)));
+    }
+    match matches.get_one::<String>("resolver") {
+        Some(command) => Box::new(CommandResolver::new(command, path_resolver)),
+        None => Box::new(path_resolver),
+    }
+}
+
+/// The files under the roots that differ from `reference` or are untracked (and not ignored)
+fn git_changed_files<'a>(roots: impl Iterator<Item = &'a String>, reference: &str) -> Result<HashSet<PathBuf>> {
+    let mut files = HashSet::new();
+    for root in roots {
+        files.extend(git_files(root, &["diff", "--name-only", "--relative", reference])?);
+        files.extend(git_files(root, &["ls-files", "--others", "--exclude-standard"])?);
+    }
+    Ok(files)
+}
 
-            return Ok(Some(new_interpreter_line));
+/// Runs `git ARGS -z` on `root` (a directory or a single file) and joins the paths it lists onto `root`,
+/// so they compare equal to the ones the walk produces
+fn git_files(root: &str, args: &[&str]) -> Result<HashSet<PathBuf>> {
+    let root = Path::new(root);
+    let (dir, pathspec) = if root.is_dir() {
+        (root, ".".as_ref())
+    } else {
+        (root.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")), root.file_name().unwrap_or_default())
+    };
+    let output = SysCommand::new("git").arg("-C").arg(dir).args(args).arg("-z").arg("--").arg(pathspec).output()
+        .context("could not run git")?;
+    if !output.status.success() {
+        bail!("git {} failed in {}: {}", args.join(" "), dir.display(), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let listed = output.stdout.split(|&b| b == 0).filter(|name| !name.is_empty());
+    Ok(if root.is_dir() {
+        listed.map(|name| root.join(String::from_utf8_lossy(name).as_ref())).collect()
+    } else {
+        listed.take(1).map(|_| root.to_path_buf()).collect()
+    })
+}
+
+/// Runs the pre-hook for one root, returning the extra search directories and NAME=path mappings it printed
+fn run_pre_hook(hook: &str, root: &str) -> Result<(Vec<PathBuf>, HashMap<String, String>)> {
+    let output = SysCommand::new("sh").arg("-c").arg(format!("{} \"$@\"", hook)).arg("sh").arg(root).output()?;
+    if !output.status.success() {
+        bail!("pre-hook failed for {} ({})", root, output.status);
+    }
+    let mut extra_dirs = Vec::new();
+    let mut mappings = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match line.split_once('=') {
+            Some((name, path)) => { mappings.insert(name.to_string(), path.to_string()); }
+            None => extra_dirs.push(PathBuf::from(line)),
         }
     }
+    Ok((extra_dirs, mappings))
+}
 
-    Ok(None)
+struct PrintObserver {
+    print_hashes: bool,
+    post_hook: Option<String>,
+    hook_failures: AtomicUsize,
+    /// With --group-by-dir, patched files are counted per directory instead of printed
+    patched_by_dir: Option<Mutex<BTreeMap<PathBuf, usize>>>,
+    /// How many times -v was given
+    verbosity: u8,
 }
 
-fn which_in_path(program: &str, path_env: &str) -> Result<String> {
-    let paths = env::split_paths(path_env);
-    for dir in paths {
-        let full_path = dir.join(program);
-        if full_path.exists() && full_path.is_file() {
-            return Ok(full_path.to_string_lossy().to_string());
+fn print_timings(timings: &Timings) {
+    let by_thread = timings.by_thread();
+    print!("{:<12}", "thread");
+    for phase in Phase::ALL {
+        print!(" {:>15}", phase.name());
+    }
+    println!();
+    let mut total = [Duration::ZERO; Phase::ALL.len()];
+    for (thread, phases) in &by_thread {
+        print!("{:<12}", thread);
+        for (index, time) in phases.iter().enumerate() {
+            print!(" {:>14.3}s", time.as_secs_f64());
+            total[index] += *time;
         }
+        println!();
+    }
+    print!("{:<12}", "total");
+    for time in total {
+        print!(" {:>14.3}s", time.as_secs_f64());
+    }
+    println!();
+}
+
+/// Each directory with patched files, indented below the closest listed ancestor
+fn print_patched_by_dir(patched_by_dir: &BTreeMap<PathBuf, usize>) {
+    // the tree is one entry, for --stable-output
+    let mut tree = String::new();
+    let mut ancestors: Vec<&Path> = Vec::new();
+    for (dir, count) in patched_by_dir {
+        while ancestors.last().is_some_and(|ancestor| !dir.starts_with(ancestor)) {
+            ancestors.pop();
+        }
+        let shown = ancestors.last().and_then(|ancestor| dir.strip_prefix(ancestor).ok()).unwrap_or(dir);
+        tree += &format!("{}{}: {} patched\n", "    ".repeat(ancestors.len()), shown.display(), count);
+        ancestors.push(dir);
+    }
+    let dirs = if patched_by_dir.len() == 1 { "directory" } else { "directories" };
+    say!("{}{} file(s) patched in {} {}", tree, patched_by_dir.values().sum::<usize>(), patched_by_dir.len(), dirs);
+}
+
+impl Observer for PrintObserver {
+    fn file_patched(&self, patched: &PatchedFile) {
+        let path = &patched.path;
+        if let Some(patched_by_dir) = &self.patched_by_dir {
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            *patched_by_dir.lock().unwrap().entry(dir).or_insert(0) += 1;
+        } else {
+            let mut lines = Vec::new();
+            if let Some(wrapped) = &patched.wrapped {
+                lines.push(format!("{}: wrapped {}, which now runs as {}", path.display(), wrapped.display(), patched.new_shebang));
+            } else if patched.new_shebang != patched.old_shebang {
+                lines.push(format!("{}: shebang updated to {}", path.display(), patched.new_shebang));
+            }
+            if patched.tokens_replaced > 0 {
+                lines.push(format!("{}: replaced {} interpreter token(s)", path.display(), patched.tokens_replaced));
+            }
+            if self.print_hashes
+                && let Some(hashes) = &patched.hashes
+            {
+                lines.push(format!("    sha256 {} -> {} (body {})", hashes.before, hashes.after, hashes.body));
+            }
+            // one entry, so --stable-output keeps the hashes under their file
+            if !lines.is_empty() {
+                say!("{}", lines.join("\n"));
+            }
+        }
+
+        if let Some(hook) = &self.post_hook {
+            let status = SysCommand::new("sh").arg("-c").arg(format!("{} \"$@\"", hook)).arg("sh").arg(path).status();
+            let problem = match status {
+                Ok(status) if status.success() => return,
+                Ok(status) => status.to_string(),
+                Err(error) => error.to_string(),
+            };
+            eprintln!("{}: error: post-hook failed ({})", path.display(), problem);
+            self.hook_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn file_skipped(&self, skipped: &SkippedFile) {
+        if self.verbosity > 0 {
+            say!("{}: skipped [{}]: {}", skipped.path.display(), skipped.code.as_str(), skipped.reason);
+        }
+    }
+
+    fn warning(&self, path: &Path, message: &str) {
+        eprintln!("{}: warning: {}", path.display(), message);
+    }
+
+    fn file_errored(&self, _path: &Path, error: &PatchError) {
+        // the error already names the file
+        eprintln!("error: {}", error);
     }
-    bail!("Could not find {} in given path", program);
 }
@@ -0,0 +1,122 @@
+//! A JSON record of every change a run made, for auditing and for undoing or replaying it later
+use std::{fs, path::{Path, PathBuf}, time::SystemTime};
+#[cfg(feature = "walk")]
+use std::io::{self, BufRead, BufReader};
+use serde::{Deserialize, Serialize};
+use crate::{PatchedFile, Result};
+#[cfg(feature = "walk")]
+use crate::{Options, process::replace_file};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool: String,
+    pub changes: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub old_shebang: String,
+    pub new_shebang: String,
+    pub sha256_before: Option<String>,
+    pub sha256_after: Option<String>,
+    /// Hash of everything after the shebang line (the same before and after)
+    #[serde(default)]
+    pub sha256_body: Option<String>,
+    /// RFC 3339, UTC
+    pub timestamp: String,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self { tool: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string(), changes: Vec::new() }
+    }
+
+    /// Records a change, stamped with the current time
+    pub fn record(&mut self, patched: &PatchedFile) {
+        self.changes.push(ManifestEntry {
+            path: patched.path.clone(),
+            old_shebang: patched.old_shebang.clone(),
+            new_shebang: patched.new_shebang.clone(),
+            sha256_before: patched.hashes.as_ref().map(|h| h.before.clone()),
+            sha256_after: patched.hashes.as_ref().map(|h| h.after.clone()),
+            sha256_body: patched.hashes.as_ref().map(|h| h.body.clone()),
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        });
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text).map_err(std::io::Error::from)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        fs::write(path, text + "\n")?;
+        Ok(())
+    }
+}
+
+/// What `Manifest::apply` did with one change
+#[cfg(feature = "walk")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Applied {
+    Patched,
+    /// The file already starts with the new shebang
+    AlreadyDone,
+    /// The file starts with something other than the old shebang, so it was left alone
+    Mismatch { found: String },
+    Missing,
+}
+
+#[cfg(feature = "walk")]
+impl Manifest {
+    /// Makes the recorded changes again, e.g. to a copy of the tree on another machine, resolving relative
+    /// paths against `dir`. Only the shebang lines are replaced, and only where the old one is still there
+    /// (tokens replaced in a file's body aren't recorded, so they aren't replayed either)
+    pub fn apply(&self, dir: &Path, options: &Options) -> Result<Vec<(PathBuf, Applied)>> {
+        let mut applied = Vec::new();
+        for change in &self.changes {
+            let path = dir.join(change.path.strip_prefix(".").unwrap_or(&change.path));
+            let outcome = apply_change(&path, change, options).map_err(|error| error.in_file(&path, Some(&change.old_shebang)))?;
+            applied.push((path, outcome));
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(feature = "walk")]
+fn apply_change(path: &Path, change: &ManifestEntry, options: &Options) -> Result<Applied> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Applied::Missing),
+        Err(error) => return Err(error.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut first_line = Vec::new();
+    reader.read_until(b'\n', &mut first_line)?;
+    let line = String::from_utf8_lossy(&first_line);
+    let shebang = line.trim_end();
+    // a shebang that was added has no old line to replace
+    let added = change.old_shebang.is_empty();
+    if shebang == change.new_shebang {
+        return Ok(Applied::AlreadyDone);
+    }
+    if added == shebang.starts_with("#!") || (!added && shebang != change.old_shebang) {
+        return Ok(Applied::Mismatch { found: shebang.to_string() });
+    }
+    let metadata = fs::metadata(path)?;
+    replace_file(path, &metadata, options, |out| {
+        out.write_all(change.new_shebang.as_bytes())?;
+        if added {
+            out.write_all(b"\n")?;
+            out.write_all(&first_line)?;
+        } else {
+            // keeps the original line ending
+            out.write_all(&first_line[change.old_shebang.len()..])?;
+        }
+        io::copy(&mut reader, out)?;
+        Ok(())
+    })?;
+    Ok(Applied::Patched)
+}
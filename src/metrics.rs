@@ -0,0 +1,63 @@
+//! Run metrics in the Prometheus text format, for the node_exporter textfile collector and
+//! other scrapers that pick up job metrics from a file
+use std::{fmt::Write, fs, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+use crate::{PatchReport, Result};
+
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    /// Executable files that were looked at, whatever happened to them
+    pub scanned: usize,
+    pub patched: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub unstable: usize,
+    pub denied: usize,
+    pub read_only: usize,
+    pub interrupted: bool,
+}
+
+impl Metrics {
+    /// Adds one root's report
+    pub fn record(&mut self, report: &PatchReport) {
+        self.scanned += report.patched.len() + report.skipped.len() + report.errors.len() + report.read_only.len();
+        self.patched += report.patched.len();
+        self.skipped += report.skipped.len();
+        self.errors += report.errors.len();
+        self.unstable += report.unstable.len();
+        self.denied += report.denied.len();
+        self.read_only += report.read_only.len();
+        self.interrupted |= report.interrupted;
+    }
+
+    pub fn render(&self, duration: Duration) -> String {
+        let finished = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let metrics: [(&str, &str, f64); 10] = [
+            ("files_scanned", "Executable files inspected", self.scanned as f64),
+            ("files_patched", "Files whose shebang or interpreter tokens were rewritten", self.patched as f64),
+            ("files_skipped", "Files left alone (no shebang, already patched, ...)", self.skipped as f64),
+            ("errors", "Files that could not be patched", self.errors as f64),
+            ("unstable", "Files whose rewrite is not idempotent", self.unstable as f64),
+            ("denied", "Paths skipped for lack of permissions", self.denied as f64),
+            ("read_only", "Files that would be patched, but are on a read-only filesystem", self.read_only as f64),
+            ("interrupted", "1 if the run was stopped by a signal", f64::from(u8::from(self.interrupted))),
+            ("duration_seconds", "Wall-clock time of the run", duration.as_secs_f64()),
+            ("last_run_timestamp_seconds", "When the run finished, in seconds since the epoch", finished.as_secs_f64()),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in metrics {
+            let _ = writeln!(text, "# HELP patchshebangs_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE patchshebangs_{} gauge", name);
+            let _ = writeln!(text, "patchshebangs_{} {}", name, value);
+        }
+        text
+    }
+
+    /// Written to a temp file first and renamed into place, so a scrape never sees half a file
+    pub fn save(&self, path: &Path, duration: Duration) -> Result<()> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+        fs::write(&temp_path, self.render(duration))?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! The `#! nix-shell -i INTERPRETER -p PACKAGES...` lines that follow a `#!/usr/bin/env nix-shell`
+//! shebang. nix-shell reads them for its own arguments, and runs the script with the `-i` interpreter
+//! inside the environment they describe; `Options::nix_shell` decides what patching does with them
+use std::{io::{self, BufRead}, path::Path};
+use crate::{NixShellPolicy, Options, PatchError, Resolver, Result, resolve_interpreter, shebang};
+
+/// The nix-shell lines after a shebang, as read (line breaks included) and as rewritten
+pub(crate) struct Continuation {
+    pub original: String,
+    pub new: String,
+}
+
+/// Whether `Options::nix_shell` means reading the lines after `first_line` at all
+pub(crate) fn wanted(first_line: &[u8], options: &Options) -> bool {
+    options.nix_shell != NixShellPolicy::FirstLine && std::str::from_utf8(first_line).is_ok_and(is_nix_shell)
+}
+
+/// Reads the `#!` lines that directly follow the shebang (and the one line after them, which is dropped)
+pub(crate) fn read_continuation(reader: &mut dyn BufRead) -> io::Result<Vec<u8>> {
+    let mut continuation = Vec::new();
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 && line.starts_with(b"#!") {
+        continuation.append(&mut line);
+    }
+    Ok(continuation)
+}
+
+/// The `#!` lines at the start of `rest`, for content that is already in memory
+#[cfg(feature = "archive")]
+pub(crate) fn continuation_of(rest: &[u8]) -> &[u8] {
+    let mut end = 0;
+    for line in rest.split_inclusive(|&b| b == b'\n') {
+        if !line.starts_with(b"#!") {
+            break;
+        }
+        end += line.len();
+    }
+    &rest[..end]
+}
+
+fn is_nix_shell(line: &str) -> bool {
+    let Some(parsed) = shebang::parse(line.trim_end()) else {
+        return false;
+    };
+    parsed.program().is_some_and(|program| Path::new(program.text).file_name().is_some_and(|name| name == "nix-shell"))
+}
+
+/// nix-shell only takes arguments from lines like `#! nix-shell ...`
+fn nix_shell_arguments(line: &str) -> Option<&str> {
+    let arguments = line.strip_prefix("#!")?.trim_start().strip_prefix("nix-shell")?;
+    arguments.starts_with(char::is_whitespace).then_some(arguments)
+}
+
+/// The `-i` values (the last one is what nix-shell runs) with their byte ranges in `continuation`
+fn interpreters(continuation: &str) -> Vec<(&str, std::ops::Range<usize>)> {
+    let mut found = Vec::new();
+    let mut line_start = 0;
+    for line in continuation.split_inclusive('\n') {
+        if let Some(arguments) = nix_shell_arguments(line) {
+            let arguments_start = line_start + (line.len() - arguments.len());
+            let mut words = arguments.split_whitespace().map(|word| {
+                // split_whitespace yields subslices, so the pointer difference is the position
+                let start = arguments_start + (word.as_ptr() as usize - arguments.as_ptr() as usize);
+                (word, start..start + word.len())
+            });
+            while let Some((word, _)) = words.next() {
+                if word == "-i"
+                    && let Some(value) = words.next()
+                {
+                    found.push(value);
+                }
+            }
+        }
+        line_start += line.len();
+    }
+    found
+}
+
+/// The new shebang and nix-shell lines for `original_shebang` (a nix-shell one) followed by `continuation`.
+/// `new_shebang` is what the shebang alone would have been patched to
+pub(crate) fn rewrite<R: Resolver + ?Sized>(
+    original_shebang: &str,
+    new_shebang: String,
+    continuation: &str,
+    options: &Options,
+    resolver: &R,
+) -> Result<(String, Option<Continuation>)> {
+    let unsupported = |reason: &str| PatchError::UnsupportedShebang { shebang: original_shebang.to_string(), reason: reason.to_string() };
+    let interpreters = interpreters(continuation);
+    if interpreters.iter().any(|(value, _)| value.starts_with(['"', '\''])) {
+        return Err(unsupported("quoted nix-shell -i interpreter"));
+    }
+    match options.nix_shell {
+        NixShellPolicy::FirstLine => Ok((new_shebang, None)),
+        // the wrapper runs the original, whose lines have to stay as they are
+        NixShellPolicy::Interpreter if options.wrap_instead => Ok((new_shebang, None)),
+        NixShellPolicy::Interpreter => {
+            let mut new = continuation.to_string();
+            // back to front, so the ranges of the earlier ones stay valid
+            for (value, range) in interpreters.into_iter().rev() {
+                if !options.update && value.starts_with("/nix/store") {
+                    continue;
+                }
+                let program = Path::new(value).file_name().and_then(|name| name.to_str()).unwrap_or(value);
+                let resolved = resolve_interpreter(program, options, resolver)?;
+                // nix-shell pastes the interpreter into a command line unquoted, so `busybox sh` works but spaces in a path don't
+                if resolved[0].contains(char::is_whitespace) {
+                    return Err(PatchError::WhitespaceInInterpreter { path: resolved[0].clone() });
+                }
+                new.replace_range(range, &resolved.join(" "));
+            }
+            Ok((new_shebang, Some(Continuation { original: continuation.to_string(), new })))
+        }
+        NixShellPolicy::Direct => {
+            let Some((value, _)) = interpreters.last() else {
+                return Err(unsupported("no nix-shell -i interpreter to run directly"));
+            };
+            // rewritten like a `#!/bin/bash` shebang would be; the nix-shell lines are comments to the interpreter now
+            match crate::rewrite_line(&format!("#!{}", value), options, resolver)? {
+                crate::Rewrite::Line(line) => Ok((line, None)),
+                crate::Rewrite::Malformed(problem) => Err(unsupported(&problem)),
+            }
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Patching container images: the scripts inside each layer of an OCI image layout, or of a
+//! `docker save` archive, are rewritten and every digest that depends on them is recomputed
+//! (layer digests, the config's `rootfs.diff_ids`, manifests, indexes)
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::{Options, PatchError, PatchEvent, PatchReport, Resolver, Result, archive::{Compression, Encoder, decoder, repack_tar}, dispatch, process::{sha256_hex, to_hex}};
+
+/// Patches the image at `path`: an OCI image layout directory, or a tar archive as written by
+/// `docker save` (either format). Blobs that are no longer referenced are left in place.
+/// Like `patch_tree`, problems with single scripts are collected in the report.
+pub fn patch_image<R: Resolver + ?Sized>(path: &Path, options: &Options, resolver: &R) -> Result<PatchReport> {
+    let observer = options.observer();
+    let mut report = PatchReport::default();
+    let mut image = Image { options, resolver, display: path.to_path_buf(), handle: &mut |event| dispatch(event, observer, &mut report) };
+    let result = if path.is_dir() { image.patch_dir(path).map(|_| ()) } else { image.patch_archive(path) };
+    if let Err(error) = result {
+        let error = error.in_file(path, None);
+        observer.file_errored(path, &error);
+        return Err(error);
+    }
+    Ok(report)
+}
+
+struct Image<'a, R: Resolver + ?Sized> {
+    options: &'a Options,
+    resolver: &'a R,
+    /// What member paths are reported under
+    display: PathBuf,
+    handle: &'a mut dyn FnMut(PatchEvent),
+}
+
+impl<R: Resolver + ?Sized> Image<'_, R> {
+    /// A `docker save` tarball is unpacked next to itself, patched there, and packed up again
+    /// (sorted, with zeroed timestamps and owners, so the result is reproducible)
+    fn patch_archive(&mut self, path: &Path) -> Result<()> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let work = path.with_file_name(format!(".{}.patchShebangs-oci", file_name));
+        let temp_path = path.with_file_name(format!(".{}.patchShebangs-tmp", file_name));
+        let _ = fs::remove_dir_all(&work);
+        let result = (|| -> Result<()> {
+            tar::Archive::new(BufReader::new(File::open(path)?)).unpack(&work)?;
+            if !self.patch_dir(&work)? {
+                return Ok(());
+            }
+            let mut builder = tar::Builder::new(BufWriter::new(File::create(&temp_path)?));
+            let mut entries: Vec<_> = walkdir::WalkDir::new(&work).min_depth(1).into_iter().collect::<std::result::Result<_, _>>()?;
+            entries.sort_by(|a, b| a.path().cmp(b.path()));
+            for entry in entries {
+                let name = entry.path().strip_prefix(&work).unwrap();
+                let metadata = entry.metadata()?;
+                let mut header = tar::Header::new_ustar();
+                header.set_mode(tar_mode(&metadata));
+                header.set_mtime(0);
+                if metadata.is_dir() {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    builder.append_data(&mut header, name, io::empty())?;
+                } else if metadata.is_symlink() {
+                    // docker save links layers shared between images instead of storing them twice
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, name, fs::read_link(entry.path())?)?;
+                } else {
+                    header.set_size(metadata.len());
+                    builder.append_data(&mut header, name, File::open(entry.path())?)?;
+                }
+            }
+            builder.into_inner()?.flush()?;
+            fs::rename(&temp_path, path)?;
+            Ok(())
+        })();
+        let _ = fs::remove_dir_all(&work);
+        let _ = fs::remove_file(&temp_path);
+        result
+    }
+
+    /// Returns whether anything changed
+    fn patch_dir(&mut self, dir: &Path) -> Result<bool> {
+        let index_path = dir.join("index.json");
+        let manifest_path = dir.join("manifest.json");
+        let mut changed = false;
+        // old blob digest -> new one, for the manifest.json docker puts next to an OCI layout
+        let mut replaced = HashMap::new();
+        if index_path.exists() {
+            let mut index = read_json(&index_path)?;
+            for descriptor in index["manifests"].as_array_mut().into_iter().flatten() {
+                changed |= self.patch_descriptor(dir, descriptor, &mut replaced)?;
+            }
+            if changed {
+                write_json(&index_path, &index)?;
+            }
+        }
+        if manifest_path.exists() {
+            let mut manifest = read_json(&manifest_path)?;
+            let mut manifest_changed = false;
+            // docker save layer file -> its new diff_id, or None when nothing in it changed
+            let mut layers = HashMap::new();
+            for image in manifest.as_array_mut().into_iter().flatten() {
+                manifest_changed |= if index_path.exists() {
+                    rename_blobs(image, &replaced)
+                } else {
+                    self.patch_docker_image(dir, image, &mut layers)?
+                };
+            }
+            if manifest_changed {
+                write_json(&manifest_path, &manifest)?;
+                changed = true;
+            }
+        }
+        if !index_path.exists() && !manifest_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "neither an OCI image layout (index.json) nor a docker archive (manifest.json)").into());
+        }
+        Ok(changed)
+    }
+
+    /// Patches what an index or manifest descriptor points at, updating its digest and size
+    fn patch_descriptor(&mut self, dir: &Path, descriptor: &mut Value, replaced: &mut HashMap<String, String>) -> Result<bool> {
+        let media_type = descriptor["mediaType"].as_str().unwrap_or_default().to_string();
+        let digest = descriptor["digest"].as_str().unwrap_or_default().to_string();
+        let blob_path = blob_path(dir, &digest)?;
+        let mut blob = read_json(&blob_path)?;
+        let changed = match media_type.as_str() {
+            "application/vnd.oci.image.index.v1+json" | "application/vnd.docker.distribution.manifest.list.v2+json" => {
+                let mut changed = false;
+                for child in blob["manifests"].as_array_mut().into_iter().flatten() {
+                    changed |= self.patch_descriptor(dir, child, replaced)?;
+                }
+                changed
+            }
+            "application/vnd.oci.image.manifest.v1+json" | "application/vnd.docker.distribution.manifest.v2+json" => {
+                self.patch_manifest(dir, &mut blob, replaced)?
+            }
+            _ => false,
+        };
+        if changed {
+            let (new_digest, size) = write_blob(dir, &serde_json::to_vec(&blob).map_err(io::Error::from)?)?;
+            replaced.insert(digest, new_digest.clone());
+            descriptor["digest"] = new_digest.into();
+            descriptor["size"] = size.into();
+        }
+        Ok(changed)
+    }
+
+    fn patch_manifest(&mut self, dir: &Path, manifest: &mut Value, replaced: &mut HashMap<String, String>) -> Result<bool> {
+        let mut diff_ids = HashMap::new();
+        for (index, layer) in manifest["layers"].as_array_mut().into_iter().flatten().enumerate() {
+            let media_type = layer["mediaType"].as_str().unwrap_or_default();
+            let Some(compression) = layer_compression(media_type) else { continue };
+            let digest = layer["digest"].as_str().unwrap_or_default().to_string();
+            let display = self.display.join(short_digest(&digest));
+            let Some(patched) = self.patch_layer(&blob_path(dir, &digest)?, &dir.join("blobs/sha256"), compression, &display)? else { continue };
+            replaced.insert(digest, patched.digest.clone());
+            layer["digest"] = patched.digest.into();
+            layer["size"] = patched.size.into();
+            diff_ids.insert(index, patched.diff_id);
+        }
+        if diff_ids.is_empty() {
+            return Ok(false);
+        }
+        let config_digest = manifest["config"]["digest"].as_str().unwrap_or_default().to_string();
+        let mut config = read_json(&blob_path(dir, &config_digest)?)?;
+        for (index, diff_id) in diff_ids {
+            config["rootfs"]["diff_ids"][index] = diff_id.into();
+        }
+        let (new_digest, size) = write_blob(dir, &serde_json::to_vec(&config).map_err(io::Error::from)?)?;
+        replaced.insert(config_digest, new_digest.clone());
+        manifest["config"]["digest"] = new_digest.into();
+        manifest["config"]["size"] = size.into();
+        Ok(true)
+    }
+
+    /// The pre-OCI `docker save` format: uncompressed `<id>/layer.tar` files and a `<digest>.json` config
+    /// `layers` carries the layers already handled over from the other images in the archive
+    fn patch_docker_image(&mut self, dir: &Path, image: &mut Value, layers: &mut HashMap<PathBuf, Option<String>>) -> Result<bool> {
+        let mut diff_ids = HashMap::new();
+        for (index, layer) in image["Layers"].as_array().into_iter().flatten().enumerate() {
+            let name = layer.as_str().unwrap_or_default();
+            // a layer shared with another image (or listed twice) is a symlink to the one file, which is
+            // patched once and gets the same new diff_id everywhere
+            let layer_path = fs::canonicalize(dir.join(name))?;
+            let diff_id = match layers.get(&layer_path) {
+                Some(diff_id) => diff_id.clone(),
+                None => {
+                    let temp_dir = layer_path.parent().unwrap_or(dir).to_path_buf();
+                    let patched = self.patch_layer(&layer_path, &temp_dir, Compression::None, &self.display.join(name))?;
+                    if let Some(patched) = &patched {
+                        fs::rename(temp_dir.join(patched.digest.trim_start_matches("sha256:")), &layer_path)?;
+                    }
+                    let diff_id = patched.map(|patched| patched.diff_id);
+                    layers.insert(layer_path, diff_id.clone());
+                    diff_id
+                }
+            };
+            if let Some(diff_id) = diff_id {
+                diff_ids.insert(index, diff_id);
+            }
+        }
+        if diff_ids.is_empty() {
+            return Ok(false);
+        }
+        let config_name = image["Config"].as_str().unwrap_or_default().to_string();
+        let mut config = read_json(&dir.join(&config_name))?;
+        for (index, diff_id) in diff_ids {
+            config["rootfs"]["diff_ids"][index] = diff_id.into();
+        }
+        let bytes = serde_json::to_vec(&config).map_err(io::Error::from)?;
+        let new_name = format!("{}.json", sha256_hex(&bytes));
+        fs::write(dir.join(&new_name), bytes)?;
+        image["Config"] = new_name.into();
+        Ok(true)
+    }
+
+    /// Repacks one layer into `out_dir/<new digest>`, returning its new digests, or `None` if no script in it changed
+    fn patch_layer(&mut self, path: &Path, out_dir: &Path, compression: Compression, display: &Path) -> Result<Option<PatchedLayer>> {
+        let temp_path = out_dir.join(".patchShebangs-layer-tmp");
+        let result = (|| {
+            let input = decoder(BufReader::new(File::open(path)?), compression)?;
+            let output = File::create(&temp_path)?;
+            let compressed = Hashing::new(BufWriter::new(output));
+            let uncompressed = Hashing::new(Encoder::new(compressed, compression)?);
+            let (changed, uncompressed) = repack_tar(input, uncompressed, display, self.options, self.resolver, self.handle)?;
+            if !changed {
+                return Ok(None);
+            }
+            let diff_id = uncompressed.digest();
+            let mut compressed = uncompressed.into_inner().finish()?;
+            compressed.flush()?;
+            let digest = compressed.digest();
+            let size = compressed.len;
+            fs::rename(&temp_path, out_dir.join(digest.trim_start_matches("sha256:")))?;
+            Ok(Some(PatchedLayer { digest, size, diff_id }))
+        })();
+        let _ = fs::remove_file(&temp_path);
+        result
+    }
+}
+
+struct PatchedLayer {
+    digest: String,
+    size: u64,
+    diff_id: String,
+}
+
+/// Passes writes through while hashing them
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Hashing<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    fn digest(&self) -> String {
+        format!("sha256:{}", to_hex(&self.hasher.clone().finalize()))
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn layer_compression(media_type: &str) -> Option<Compression> {
+    if media_type.ends_with("+gzip") || media_type.ends_with(".tar.gzip") {
+        Some(Compression::Gzip)
+    } else if media_type.ends_with("+zstd") {
+        Some(Compression::Zstd)
+    } else if media_type.ends_with(".tar") {
+        Some(Compression::None)
+    } else {
+        // foreign or encrypted layers can't be patched
+        None
+    }
+}
+
+/// Points docker's manifest.json (`Config` and `Layers`, as `blobs/sha256/<hex>`) at the replaced blobs
+fn rename_blobs(image: &mut Value, replaced: &HashMap<String, String>) -> bool {
+    let mut changed = false;
+    let mut rename = |value: &mut Value| {
+        let Some(hex) = value.as_str().and_then(|path| path.strip_prefix("blobs/sha256/")) else { return };
+        if let Some(new_digest) = replaced.get(&format!("sha256:{}", hex)) {
+            *value = format!("blobs/sha256/{}", new_digest.trim_start_matches("sha256:")).into();
+            changed = true;
+        }
+    };
+    rename(&mut image["Config"]);
+    image["Layers"].as_array_mut().into_iter().flatten().for_each(&mut rename);
+    changed
+}
+
+fn blob_path(dir: &Path, digest: &str) -> Result<PathBuf> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(dir.join("blobs/sha256").join(hex)),
+        _ => Err(PatchError::from(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported digest {:?}", digest)))),
+    }
+}
+
+fn short_digest(digest: &str) -> String {
+    digest.trim_start_matches("sha256:").chars().take(12).collect()
+}
+
+fn write_blob(dir: &Path, bytes: &[u8]) -> Result<(String, u64)> {
+    let hex = sha256_hex(bytes);
+    fs::write(dir.join("blobs/sha256").join(&hex), bytes)?;
+    Ok((format!("sha256:{}", hex), bytes.len() as u64))
+}
+
+#[cfg(unix)]
+fn tar_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+// nothing in a docker-save tarball needs to be executable
+#[cfg(not(unix))]
+fn tar_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.is_dir() { 0o755 } else { 0o644 }
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let text = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&text).map_err(io::Error::from)?)
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<()> {
+    fs::write(path, serde_json::to_vec(value).map_err(io::Error::from)?)?;
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! `Options::jobs`: the walk stays on the calling thread and feeds a pipeline of classifiers, which
+//! read the start of each file, and rewriters, which put patched files in place. Every queue between
+//! them is bounded (`Options::queue_depths`), so the walk can't race arbitrarily far ahead of slow
+//! writes. Events come back to the calling thread, so observers never run concurrently, and are put
+//! back in walk order there, so the output of parallel runs can be diffed (all but `Started`, which is
+//! sent as a file is queued)
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, mpsc},
+    thread,
+};
+use crate::{
+    Options, PatchError, PatchEvent, PatchIter, Resolver, Result, SkippedFile,
+    iter::{Walked, outcome_events},
+    process::{Classified, RewritePlan, classify_file, rewrite_file},
+    timings::{Phase, timed},
+};
+
+/// Runs the walk with `options.jobs` classifiers and as many rewriters, handing every event to `handle`.
+/// Files are reported in the order they were walked in, like without `jobs`.
+/// Returns whether the walk was cancelled.
+pub(crate) fn patch_events<R: Resolver + ?Sized>(
+    root: &Path,
+    options: &Options,
+    resolver: &R,
+    handle: &mut dyn FnMut(PatchEvent),
+) -> Result<bool> {
+    let mut walk = PatchIter::new(root, options, resolver);
+    // a full queue always has a file in it then, whose result is worth waiting for
+    let depth = |depth: Option<usize>| depth.unwrap_or(options.jobs * 2).max(1);
+    // every file carries its place in the walk along
+    let (path_sender, paths) = mpsc::sync_channel::<(usize, PathBuf, Option<Vec<u8>>)>(depth(options.queue_depths.classify));
+    let (plan_sender, plans) = mpsc::sync_channel::<(usize, PathBuf, RewritePlan)>(depth(options.queue_depths.rewrite));
+    let (result_sender, results) = mpsc::sync_channel(depth(options.queue_depths.report));
+    let (paths, plans) = (Mutex::new(paths), Mutex::new(plans));
+    thread::scope(|scope| {
+        for worker in 1..=options.jobs {
+            let (plan_sender, classified_sender) = (plan_sender.clone(), result_sender.clone());
+            let paths = &paths;
+            // named, so `Options::timings` can tell them apart
+            thread::Builder::new().name(format!("classifier {}", worker)).spawn_scoped(scope, move || {
+                // the lock is only held while waiting, or the classifiers would take turns
+                loop {
+                    let Ok((sequence, path, head)) = paths.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let sent = match timed(options, Phase::Classification, || classify_file(&path, head.as_deref(), options, resolver)) {
+                        Ok(Classified::Rewrite(plan)) => plan_sender.send((sequence, path, plan)).is_ok(),
+                        Ok(Classified::Done(outcome)) => classified_sender.send((sequence, outcome_events(&path, Ok(outcome), options, resolver))).is_ok(),
+                        Err(error) => classified_sender.send((sequence, outcome_events(&path, Err(error), options, resolver))).is_ok(),
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            })?;
+
+            let result_sender = result_sender.clone();
+            let plans = &plans;
+            thread::Builder::new().name(format!("rewriter {}", worker)).spawn_scoped(scope, move || {
+                loop {
+                    let Ok((sequence, path, plan)) = plans.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let outcome = rewrite_file(&path, plan, options, resolver);
+                    if result_sender.send((sequence, outcome_events(&path, outcome, options, resolver))).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        }
+        // the rewriters stop once the last classifier is done
+        drop(plan_sender);
+        drop(result_sender);
+
+        // after an error, the files already handed out are still finished and reported
+        let mut order = Reorder { next: 0, pending: BTreeMap::new(), failure: None };
+        // as many files as can be in the queues and in the workers' hands at once
+        let in_flight = depth(options.queue_depths.classify) + depth(options.queue_depths.rewrite) + depth(options.queue_depths.report) + options.jobs * 2;
+        let mut walked = 0;
+        loop {
+            // files skipped on the walk don't go through the queues, so behind one slow file they
+            // would pile up in `order` without bound; the walk waits for that file instead
+            while order.pending.len() >= in_flight && order.failure.is_none() {
+                order.receive(&results, handle);
+            }
+            if order.failure.is_some() {
+                break;
+            }
+            match walk.next_file() {
+                Ok(Some(Walked::File(path, head))) => {
+                    // straight away, not in walk order: it has to come before a worker touches the file
+                    handle(PatchEvent::Started(path.clone()));
+                    // results are taken while waiting for room, or a full report queue would stall the pipeline
+                    let mut file = (walked, path, head);
+                    loop {
+                        match path_sender.try_send(file) {
+                            Ok(()) => break,
+                            Err(mpsc::TrySendError::Full(full)) => {
+                                file = full;
+                                if !order.receive(&results, handle) {
+                                    break;
+                                }
+                            }
+                            Err(mpsc::TrySendError::Disconnected(_)) => {
+                                order.failure = Some(workers_gone());
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok(Some(Walked::Denied(path))) => order.collect((walked, Ok(vec![PatchEvent::Denied(path)])), handle),
+                Ok(Some(Walked::Skipped(path, code))) => order.collect((walked, Ok(vec![PatchEvent::Skipped(SkippedFile::new(&path, code))])), handle),
+                Ok(None) => break,
+                Err(error) => {
+                    order.failure = Some(error);
+                    break;
+                }
+            }
+            walked += 1;
+            for result in results.try_iter() {
+                order.collect(result, handle);
+            }
+            if order.failure.is_some() {
+                break;
+            }
+        }
+        // lets the pipeline run dry and the workers exit
+        drop(path_sender);
+        for result in results.iter() {
+            order.collect(result, handle);
+        }
+        match order.failure {
+            Some(error) => Err(error),
+            None => Ok(walk.was_cancelled()),
+        }
+    })
+}
+
+/// The classifiers and rewriters only stop early by panicking
+fn workers_gone() -> PatchError {
+    io::Error::other("the worker threads exited").into()
+}
+
+/// Holds results back until those of every file walked before them are in. Only the files in the
+/// pipeline can be missing, and the walk waits while more than the queue depths are held
+struct Reorder {
+    next: usize,
+    pending: BTreeMap<usize, Result<Vec<PatchEvent>>>,
+    failure: Option<PatchError>,
+}
+
+impl Reorder {
+    /// Waits for the next result from the pipeline. Returns false (with the run failed) when there
+    /// can't be one, since every worker is gone
+    fn receive(&mut self, results: &mpsc::Receiver<(usize, Result<Vec<PatchEvent>>)>, handle: &mut dyn FnMut(PatchEvent)) -> bool {
+        match results.recv() {
+            Ok(result) => {
+                self.collect(result, handle);
+                true
+            }
+            Err(_) => {
+                self.failure.get_or_insert_with(workers_gone);
+                false
+            }
+        }
+    }
+
+    fn collect(&mut self, (sequence, result): (usize, Result<Vec<PatchEvent>>), handle: &mut dyn FnMut(PatchEvent)) {
+        self.pending.insert(sequence, result);
+        while let Some(result) = self.pending.remove(&self.next) {
+            self.next += 1;
+            match result {
+                Ok(events) => events.into_iter().for_each(&mut *handle),
+                Err(error) => {
+                    self.failure.get_or_insert(error);
+                }
+            }
+        }
+    }
+}
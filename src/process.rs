@@ -0,0 +1,513 @@
+//! Patching a single file on disk: reading its first line, rewriting or wrapping it and putting the result in place
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use sha2::{Digest, Sha256};
+use crate::{ContentHashes, MtimePolicy, Options, PatchError, Resolver, Result, Rewrite, SkipCode, nixshell::{self, Continuation}, rewrite_line, shebang, timings::{Phase, timed}};
+
+pub(crate) enum Outcome {
+    Patched { old: String, new: String, hashes: Option<ContentHashes>, tokens_replaced: usize, wrapped: Option<PathBuf> },
+    Skipped(SkipCode),
+    Malformed(String),
+    /// Would have been patched, but the file is on a read-only filesystem
+    ReadOnly { old: String, new: String },
+}
+
+/// What reading the start of a file decided
+pub(crate) enum Classified {
+    Done(Outcome),
+    /// The file gets replaced, which `rewrite_file` does
+    Rewrite(RewritePlan),
+}
+
+pub(crate) struct RewritePlan {
+    first_line: Vec<u8>,
+    original: String,
+    new: String,
+    /// Set when the shebang stays as it is, and only tokens in the body might need replacing
+    skip_reason: Option<SkipCode>,
+    continuation: Option<Continuation>,
+}
+
+/// `head` are the file's first (up to `PROBE_LEN`) bytes, if they were already read
+pub(crate) fn process_file<R: Resolver + ?Sized>(path: &Path, head: Option<&[u8]>, options: &Options, resolver: &R) -> Result<Outcome> {
+    match classify_file(path, head, options, resolver)? {
+        Classified::Done(outcome) => Ok(outcome),
+        Classified::Rewrite(plan) => rewrite_file(path, plan, options, resolver),
+    }
+}
+
+/// The first half of `process_file`: only reads the file (unless a shebang is added to it), so the
+/// parallel pipeline can do it while other files are still being written
+pub(crate) fn classify_file<R: Resolver + ?Sized>(path: &Path, head: Option<&[u8]>, options: &Options, resolver: &R) -> Result<Classified> {
+    if options.wrap_instead && is_wrapped_original(path) {
+        return Ok(Classified::Done(Outcome::Skipped(SkipCode::WrappedOriginal)));
+    }
+    // most candidates are binaries, which a few bytes on the stack are enough to rule out;
+    // read bytes, since those are rarely valid UTF-8
+    let mut probe = [0; PROBE_LEN];
+    let (probed, file) = match head {
+        Some(head) => {
+            probe[..head.len()].copy_from_slice(head);
+            (head.len(), None)
+        }
+        None => {
+            let mut file = File::open(path)?;
+            (read_up_to(&mut file, &mut probe)?, Some(file))
+        }
+    };
+    throttle(options, probed);
+    if !probe[..probed].starts_with(b"#!") {
+        return add_missing_shebang(path, &probe[..probed], options, resolver).map(Classified::Done);
+    }
+    let mut first_line = match probe[..probed].iter().position(|&b| b == b'\n') {
+        Some(end) => probe[..=end].to_vec(),
+        None => probe[..probed].to_vec(),
+    };
+    if probed == PROBE_LEN && !first_line.ends_with(b"\n") {
+        let before = first_line.len();
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let mut file = File::open(path)?;
+                file.seek(io::SeekFrom::Start(PROBE_LEN as u64))?;
+                file
+            }
+        };
+        BufReader::new(file).read_until(b'\n', &mut first_line)?;
+        throttle(options, first_line.len() - before);
+    }
+    let continuation = if nixshell::wanted(&first_line, options) {
+        let mut file = File::open(path)?;
+        file.seek(io::SeekFrom::Start(first_line.len() as u64))?;
+        nixshell::read_continuation(&mut BufReader::new(file))?
+    } else {
+        Vec::new()
+    };
+    match plan_line(path, first_line.clone(), &continuation, options, resolver)? {
+        LinePlan::Done(outcome) => Ok(Classified::Done(outcome)),
+        LinePlan::Rewrite { original, new, skip_reason, continuation } => {
+            if skip_reason.is_none() && on_read_only_filesystem(path) {
+                return Ok(Classified::Done(Outcome::ReadOnly { old: original, new }));
+            }
+            Ok(Classified::Rewrite(RewritePlan { first_line, original, new, skip_reason, continuation }))
+        }
+    }
+}
+
+/// The second half of `process_file`, which puts the patched file in place
+pub(crate) fn rewrite_file<R: Resolver + ?Sized>(path: &Path, plan: RewritePlan, options: &Options, resolver: &R) -> Result<Outcome> {
+    let RewritePlan { first_line, original: original_shebang, new: new_interpreter_line, skip_reason, continuation } = plan;
+    // the nix-shell lines are replaced along with the shebang, and the body starts after them
+    let first_line_len = first_line.len() + continuation.as_ref().map_or(0, |continuation| continuation.original.len());
+    let read_only = || Outcome::ReadOnly { old: original_shebang.clone(), new: new_interpreter_line.clone() };
+    if options.wrap_instead {
+        let (wrapped, hashes) = match wrap_file(path, &new_interpreter_line, options, resolver) {
+            Err(PatchError::Io(error)) if error.kind() == io::ErrorKind::ReadOnlyFilesystem => return Ok(read_only()),
+            result => result?,
+        };
+        return Ok(Outcome::Patched { old: original_shebang, new: new_interpreter_line, hashes, tokens_replaced: 0, wrapped: Some(wrapped) });
+    }
+
+    // the rest of the file is streamed through in chunks, so memory stays bounded however big it is;
+    // a shebang that stays as it is only gets rewritten if tokens turn up in a dry pass first
+    let body = || -> io::Result<File> {
+        let mut body = File::open(path)?;
+        body.seek(io::SeekFrom::Start(first_line_len as u64))?;
+        Ok(body)
+    };
+    if let Some(code) = skip_reason {
+        if options.tokens.is_empty() || copy_replacing(path, &mut body()?, &mut io::sink(), &mut None, options, resolver)? == 0 {
+            return Ok(Outcome::Skipped(code));
+        }
+        if on_read_only_filesystem(path) {
+            return Ok(read_only());
+        }
+    }
+    let shebang = if skip_reason.is_some() { original_shebang.clone() } else { new_interpreter_line.clone() };
+    let metadata = fs::metadata(path)?;
+    let mut hashes = options.hash_contents.then(StreamHashes::default);
+    let mut body = body()?;
+    let written = replace_file(path, &metadata, options, |out| {
+        if let Some(hashes) = &mut hashes {
+            hashes.before.update(&first_line);
+        }
+        // keeps the original line ending (and any trailing whitespace)
+        emit(out, shebang.as_bytes(), &mut hashes, options)?;
+        emit(out, &first_line[original_shebang.len()..], &mut hashes, options)?;
+        if let Some(continuation) = &continuation {
+            if let Some(hashes) = &mut hashes {
+                hashes.before.update(continuation.original.as_bytes());
+            }
+            emit(out, continuation.new.as_bytes(), &mut hashes, options)?;
+        }
+        copy_replacing(path, &mut body, out, &mut hashes, options, resolver)
+    });
+    let tokens_replaced = match written {
+        Err(PatchError::Io(error)) if error.kind() == io::ErrorKind::ReadOnlyFilesystem => return Ok(read_only()),
+        result => result?,
+    };
+    let hashes = hashes.map(StreamHashes::finish);
+
+    Ok(Outcome::Patched { old: original_shebang, new: shebang, hashes, tokens_replaced, wrapped: None })
+}
+
+/// Prepends a shebang to a file without one when `Options::add_missing_shebang` has a rule for its
+/// extension. Anything with a NUL byte in `head` is taken for a binary and left alone
+fn add_missing_shebang<R: Resolver + ?Sized>(path: &Path, head: &[u8], options: &Options, resolver: &R) -> Result<Outcome> {
+    if head.contains(&0) {
+        return Ok(Outcome::Skipped(SkipCode::Binary));
+    }
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let rule = options.add_missing_shebang.iter().find(|(rule_extension, _)| Some(rule_extension.as_str()) == extension);
+    let Some((_, program)) = rule.filter(|_| !options.wrap_instead) else {
+        return Ok(Outcome::Skipped(SkipCode::NoShebang));
+    };
+    let interpreter = if Path::new(program).is_absolute() { program.clone() } else { resolver.resolve(program).map_err(|e| e.in_file(path, None))? };
+    let new = format!("#!{}", interpreter);
+    let read_only = || Outcome::ReadOnly { old: String::new(), new: new.clone() };
+    if on_read_only_filesystem(path) {
+        return Ok(read_only());
+    }
+    let metadata = fs::metadata(path)?;
+    let mut hashes = options.hash_contents.then(StreamHashes::default);
+    let mut body = File::open(path)?;
+    let written = replace_file(path, &metadata, options, |out| {
+        emit(out, new.as_bytes(), &mut hashes, options)?;
+        emit(out, b"\n", &mut hashes, options)?;
+        copy_replacing(path, &mut body, out, &mut hashes, options, resolver)
+    });
+    let tokens_replaced = match written {
+        Err(PatchError::Io(error)) if error.kind() == io::ErrorKind::ReadOnlyFilesystem => return Ok(read_only()),
+        result => result?,
+    };
+    let hashes = hashes.map(StreamHashes::finish);
+    Ok(Outcome::Patched { old: String::new(), new, hashes, tokens_replaced, wrapped: None })
+}
+
+/// Read size of the streaming rewrite
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Fed while a file is being streamed through `copy_replacing`
+#[derive(Default)]
+struct StreamHashes {
+    before: Sha256,
+    after: Sha256,
+    body: Sha256,
+}
+
+impl StreamHashes {
+    fn finish(self) -> ContentHashes {
+        ContentHashes { before: to_hex(&self.before.finalize()), after: to_hex(&self.after.finalize()), body: to_hex(&self.body.finalize()) }
+    }
+}
+
+fn emit(out: &mut dyn Write, bytes: &[u8], hashes: &mut Option<StreamHashes>, options: &Options) -> io::Result<()> {
+    out.write_all(bytes)?;
+    throttle(options, bytes.len());
+    if let Some(hashes) = hashes {
+        hashes.after.update(bytes);
+    }
+    Ok(())
+}
+
+/// Copies the rest of `body` to `out`, replacing `Options::tokens` on the way, and returns how many
+/// were replaced. The last `longest token - 1` bytes of each chunk are held back, so a token split
+/// across two chunks is still found
+fn copy_replacing<R: Resolver + ?Sized>(
+    path: &Path,
+    body: &mut dyn Read,
+    out: &mut dyn Write,
+    hashes: &mut Option<StreamHashes>,
+    options: &Options,
+    resolver: &R,
+) -> Result<usize> {
+    let tokens: Vec<&(String, String)> = options.tokens.iter().filter(|(token, _)| !token.is_empty()).collect();
+    let held_back = tokens.iter().map(|(token, _)| token.len() - 1).max().unwrap_or(0);
+    // programs are only resolved once their token actually turns up
+    let mut resolved: Vec<Option<String>> = vec![None; tokens.len()];
+    let mut replaced = 0;
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut pending = Vec::with_capacity(CHUNK_LEN + held_back);
+    loop {
+        let read = match body.read(&mut chunk) {
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        throttle(options, read);
+        if let Some(hashes) = hashes {
+            hashes.before.update(&chunk[..read]);
+            hashes.body.update(&chunk[..read]);
+        }
+        pending.extend_from_slice(&chunk[..read]);
+        let end_of_file = read == 0;
+        let scan_until = if end_of_file { pending.len() } else { pending.len().saturating_sub(held_back) };
+
+        let mut copied = 0;
+        let mut at = if tokens.is_empty() { scan_until } else { 0 };
+        while at < scan_until {
+            let Some(index) = tokens.iter().position(|(token, _)| pending[at..].starts_with(token.as_bytes())) else {
+                at += 1;
+                continue;
+            };
+            if resolved[index].is_none() {
+                let program = &tokens[index].1;
+                let program_path = if Path::new(program).is_absolute() { program.clone() } else { resolver.resolve(program).map_err(|e| e.in_file(path, None))? };
+                resolved[index] = Some(program_path);
+            }
+            emit(out, &pending[copied..at], hashes, options)?;
+            emit(out, resolved[index].as_deref().unwrap_or_default().as_bytes(), hashes, options)?;
+            at += tokens[index].0.len();
+            copied = at;
+            replaced += 1;
+        }
+        emit(out, &pending[copied..at], hashes, options)?;
+        pending.drain(..at);
+        if end_of_file {
+            return Ok(replaced);
+        }
+    }
+}
+
+/// Checked before writing, so a read-only store or mount is reported as such instead of
+/// failing halfway through replacing the file
+#[cfg(unix)]
+fn on_read_only_filesystem(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is NUL-terminated and stat is only read after statvfs filled it in
+    unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) == 0 && stat.assume_init().f_flag & libc::ST_RDONLY != 0 }
+}
+
+// EROFS from the write itself is still caught
+#[cfg(not(unix))]
+fn on_read_only_filesystem(_path: &Path) -> bool {
+    false
+}
+
+/// Enough for nearly every shebang line (Linux itself only looks at the first 256 bytes)
+pub(crate) const PROBE_LEN: usize = 256;
+
+/// Fills as much of `buffer` as the file has, returning how much that is
+fn read_up_to(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+fn throttle(options: &Options, bytes: usize) {
+    if let Some(throttle) = &options.throttle {
+        throttle.consume(bytes);
+    }
+}
+
+/// What to do with a script, decided from its first line alone (and the nix-shell lines after it)
+pub(crate) enum LinePlan {
+    Done(Outcome),
+    /// `skip_reason` is set when the shebang itself needs no change, but `Options::tokens` may still apply.
+    /// `continuation` is set when the nix-shell lines after the shebang change
+    Rewrite { original: String, new: String, skip_reason: Option<SkipCode>, continuation: Option<Continuation> },
+}
+
+/// The shebang-level decision, shared by files on disk and archive members. `continuation` are the
+/// `#!` lines following the first one, when `nixshell::wanted` asked for them (and empty otherwise)
+pub(crate) fn plan_line<R: Resolver + ?Sized>(path: &Path, first_line: Vec<u8>, continuation: &[u8], options: &Options, resolver: &R) -> Result<LinePlan> {
+    if !first_line.starts_with(b"#!") {
+        return Ok(LinePlan::Done(Outcome::Skipped(SkipCode::NoShebang)));
+    }
+    let nix_shell = nixshell::wanted(&first_line, options);
+    let first_line = String::from_utf8(first_line).map_err(|_| PatchError::NonUtf8 { path: path.to_path_buf() })?;
+
+    let original_shebang = first_line.trim_end().to_string();
+    let new_interpreter_line = match rewrite_line(&original_shebang, options, resolver).map_err(|e| e.in_file(path, Some(&original_shebang)))? {
+        Rewrite::Line(line) => line,
+        Rewrite::Malformed(problem) => return Ok(LinePlan::Done(Outcome::Malformed(problem))),
+    };
+    let (new_interpreter_line, continuation) = if nix_shell {
+        let continuation = std::str::from_utf8(continuation).map_err(|_| PatchError::NonUtf8 { path: path.to_path_buf() })?;
+        nixshell::rewrite(&original_shebang, new_interpreter_line, continuation, options, resolver).map_err(|e| e.in_file(path, Some(&original_shebang)))?
+    } else {
+        (new_interpreter_line, None)
+    };
+    let continuation = continuation.filter(|continuation| continuation.new != continuation.original);
+    let interpreter = shebang::parse(&original_shebang).map_or("", |parsed| parsed.interpreter.text);
+
+    let skip_reason = if original_shebang == new_interpreter_line && continuation.is_none() {
+        Some(SkipCode::UpToDate)
+    } else if !options.update && interpreter.starts_with("/nix/store") {
+        Some(SkipCode::StorePath)
+    } else {
+        None
+    };
+    // with tokens to replace, a shebang that needs no change doesn't mean the file doesn't
+    if let Some(code) = skip_reason
+        && (options.tokens.is_empty() || options.wrap_instead)
+    {
+        return Ok(LinePlan::Done(Outcome::Skipped(code)));
+    }
+    // a skipped shebang keeps the lines after it as they are too
+    let continuation = continuation.filter(|_| skip_reason.is_none());
+    Ok(LinePlan::Rewrite { original: original_shebang, new: new_interpreter_line, skip_reason, continuation })
+}
+
+/// `foo` is moved, untouched, to `.foo-wrapped` (like nixpkgs' wrapProgram does)
+fn wrapped_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}-wrapped", file_name))
+}
+
+fn is_wrapped_original(path: &Path) -> bool {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    file_name.starts_with('.') && file_name.ends_with("-wrapped")
+}
+
+/// Moves the script aside and puts a wrapper in its place that runs it with the interpreter of
+/// `new_interpreter_line`, leaving the original byte-identical (for signed or checksummed scripts)
+fn wrap_file<R: Resolver + ?Sized>(path: &Path, new_interpreter_line: &str, options: &Options, resolver: &R) -> Result<(PathBuf, Option<ContentHashes>)> {
+    let wrapped = wrapped_path(path);
+    if fs::symlink_metadata(&wrapped).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", wrapped.display())).into());
+    }
+    let shell = resolver.resolve("sh").map_err(|e| e.in_file(path, None))?;
+    // the kernel passes everything after the interpreter as a single argument, so the wrapper does too
+    let (interpreter, argument) = match new_interpreter_line.trim_start_matches("#!").split_once(char::is_whitespace) {
+        Some((interpreter, argument)) => (interpreter, Some(argument.trim())),
+        None => (new_interpreter_line.trim_start_matches("#!"), None),
+    };
+    let absolute = std::path::absolute(&wrapped)?;
+    let mut command = vec![shell_quote(interpreter)];
+    command.extend(argument.map(shell_quote));
+    command.push(shell_quote(&absolute.to_string_lossy()));
+    let wrapper = format!("#!{}\nexec {} \"$@\"\n", shell, command.join(" "));
+
+    let metadata = fs::metadata(path)?;
+    let hashes = if options.hash_contents {
+        let content = fs::read(path)?;
+        let body = content.iter().position(|&b| b == b'\n').map_or(&[][..], |i| &content[i + 1..]);
+        Some(ContentHashes { before: sha256_hex(&content), after: sha256_hex(wrapper.as_bytes()), body: sha256_hex(body) })
+    } else {
+        None
+    };
+    if options.dry_run {
+        return Ok((wrapped, hashes));
+    }
+    fs::rename(path, &wrapped)?;
+    if let Err(error) = replace_file(path, &metadata, options, |out| Ok(out.write_all(wrapper.as_bytes())?)) {
+        let _ = fs::rename(&wrapped, path);
+        return Err(error);
+    }
+    Ok((wrapped, hashes))
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Writes to a temp file next to `path` and renames it over the original (like `sed -i`),
+/// so an interrupted run never leaves a half-written script behind.
+/// With `Options::fsync`, the file is on disk before the rename and the rename is on disk before returning
+pub(crate) fn replace_file<T>(path: &Path, metadata: &fs::Metadata, options: &Options, contents: impl FnOnce(&mut dyn Write) -> Result<T>) -> Result<T> {
+    timed(options, Phase::Writing, || write_replacement(path, metadata, options, contents))
+}
+
+fn write_replacement<T>(path: &Path, metadata: &fs::Metadata, options: &Options, contents: impl FnOnce(&mut dyn Write) -> Result<T>) -> Result<T> {
+    if options.dry_run {
+        return contents(&mut io::sink());
+    }
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.patchShebangs-tmp", file_name));
+    let write = || -> Result<T> {
+        // a leftover from a killed run is safe to replace
+        let _ = fs::remove_file(&temp_path);
+        let mut temp = fs::OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+        let mut out = io::BufWriter::new(&mut temp);
+        let written = contents(&mut out)?;
+        out.flush()?;
+        drop(out);
+        copy_permissions(&temp, metadata)?;
+        if let Some(mtime) = new_mtime(metadata, options) {
+            temp.set_modified(mtime)?;
+        }
+        if options.fsync {
+            temp.sync_all()?;
+        }
+        drop(temp);
+        fs::rename(&temp_path, path)?;
+        if options.fsync {
+            sync_parent(path)?;
+        }
+        Ok(written)
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Gives the replacement of a file the original's owner and its full mode, setuid, setgid and sticky
+/// bits included, where creating it went through the umask. The owner comes first since chown clears
+/// setuid, and only root may give files away, so for everyone else it is kept as it is
+#[cfg(unix)]
+pub(crate) fn copy_permissions(file: &File, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt, fchown};
+    let created = file.metadata()?;
+    if (created.uid(), created.gid()) != (metadata.uid(), metadata.gid()) {
+        let _ = fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+    }
+    file.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7777))
+}
+
+#[cfg(not(any(unix, target_os = "wasi")))]
+pub(crate) fn copy_permissions(file: &File, metadata: &fs::Metadata) -> io::Result<()> {
+    file.set_permissions(metadata.permissions())
+}
+
+// WASI has no permission bits to keep (setting them is unsupported)
+#[cfg(target_os = "wasi")]
+pub(crate) fn copy_permissions(_file: &File, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// The mtime to give the replacement of a file with `metadata`, if not the time of writing.
+/// Kept to the nanosecond, for tools that compare high-resolution timestamps
+pub(crate) fn new_mtime(metadata: &fs::Metadata, options: &Options) -> Option<SystemTime> {
+    match options.mtime {
+        MtimePolicy::Preserve => metadata.modified().ok(),
+        MtimePolicy::Fresh => None,
+        MtimePolicy::Fixed(time) => Some(time),
+    }
+}
+
+/// Makes a rename into the directory of `path` durable
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    // directories can't be opened as files (nor need to be synced) on Windows
+    if cfg!(unix) {
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
@@ -0,0 +1,87 @@
+//! in-toto statements with a SLSA provenance predicate describing a patching run,
+//! so patched artifacts can be attested in supply-chain pipelines
+use std::{collections::{BTreeMap, BTreeSet}, fs, path::Path, time::SystemTime};
+use serde_json::{Value, json};
+use crate::{PatchedFile, Result, sbom::interpreters_in};
+
+pub const BUILD_TYPE: &str = "https://github.com/jeff-hykin/patchShebangsRust/patch-shebangs@v1";
+
+#[derive(Debug)]
+pub struct Provenance {
+    started: SystemTime,
+    /// The command line the run was started with
+    pub arguments: Vec<String>,
+    pub roots: Vec<String>,
+    pub search_path: String,
+    outputs: Vec<Value>,
+    inputs: Vec<Value>,
+    interpreters: BTreeSet<String>,
+    /// Old interpreter -> new interpreter, as seen in the rewrites
+    mappings: BTreeMap<String, String>,
+}
+
+impl Provenance {
+    pub fn new(arguments: Vec<String>, roots: Vec<String>, search_path: String) -> Self {
+        Self {
+            started: SystemTime::now(),
+            arguments,
+            roots,
+            search_path,
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+            interpreters: BTreeSet::new(),
+            mappings: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a patched file as a subject; needs `Options::hash_contents` for the digests
+    pub fn record(&mut self, patched: &PatchedFile) {
+        let name = patched.path.to_string_lossy();
+        if let Some(hashes) = &patched.hashes {
+            self.outputs.push(json!({ "name": name, "digest": { "sha256": hashes.after } }));
+            self.inputs.push(json!({ "uri": format!("file://{}", name), "digest": { "sha256": hashes.before } }));
+        }
+        let old = interpreters_in(&patched.old_shebang);
+        let new = interpreters_in(&patched.new_shebang);
+        if let (Some(old), Some(new)) = (old.last(), new.last()) {
+            self.mappings.insert(old.clone(), new.clone());
+        }
+        self.interpreters.extend(new);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let interpreters = self.interpreters.iter().map(|i| json!({ "uri": format!("file://{}", i), "annotations": { "role": "interpreter" } }));
+        json!({
+            "_type": "https://in-toto.io/Statement/v1",
+            "subject": self.outputs,
+            "predicateType": "https://slsa.dev/provenance/v1",
+            "predicate": {
+                "buildDefinition": {
+                    "buildType": BUILD_TYPE,
+                    "externalParameters": {
+                        "arguments": self.arguments,
+                        "roots": self.roots,
+                    },
+                    "internalParameters": {
+                        "searchPath": self.search_path,
+                        "mappings": self.mappings,
+                    },
+                    "resolvedDependencies": self.inputs.iter().cloned().chain(interpreters).collect::<Vec<_>>(),
+                },
+                "runDetails": {
+                    "builder": { "id": concat!("https://github.com/jeff-hykin/patchShebangsRust@", env!("CARGO_PKG_VERSION")) },
+                    "metadata": {
+                        "startedOn": humantime::format_rfc3339_seconds(self.started).to_string(),
+                        "finishedOn": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                    },
+                },
+            },
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(&self.to_json()).map_err(std::io::Error::from)?;
+        fs::write(path, text + "\n")?;
+        Ok(())
+    }
+}
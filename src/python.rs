@@ -0,0 +1,121 @@
+//! The `patch_shebangs` Python module, for packaging automation that would otherwise run the CLI
+//! as a subprocess and parse its output. Build it with maturin (see pyproject.toml)
+use std::{collections::HashMap, env, path::PathBuf};
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+use crate::{AliasResolver, Options, PathResolver};
+
+create_exception!(patch_shebangs, PatchError, PyException, "A run that could not finish, e.g. for an unreadable root");
+
+#[pyclass(name = "PatchedFile", frozen, get_all)]
+#[derive(Clone)]
+pub struct PyPatchedFile {
+    path: PathBuf,
+    old_shebang: String,
+    new_shebang: String,
+    tokens_replaced: usize,
+}
+
+#[pymethods]
+impl PyPatchedFile {
+    fn __repr__(&self) -> String {
+        format!("PatchedFile({:?}, {:?} -> {:?})", self.path, self.old_shebang, self.new_shebang)
+    }
+}
+
+#[pyclass(name = "SkippedFile", frozen, get_all)]
+#[derive(Clone)]
+pub struct PySkippedFile {
+    path: PathBuf,
+    /// The `SkipCode` as the CLI prints it, e.g. "no-shebang"
+    code: &'static str,
+    reason: String,
+}
+
+#[pymethods]
+impl PySkippedFile {
+    fn __repr__(&self) -> String {
+        format!("SkippedFile({:?}, {})", self.path, self.code)
+    }
+}
+
+#[pyclass(name = "PatchReport", frozen, get_all)]
+pub struct PyPatchReport {
+    patched: Vec<PyPatchedFile>,
+    skipped: Vec<PySkippedFile>,
+    /// One message per file that could not be patched
+    errors: Vec<String>,
+    unstable: Vec<PathBuf>,
+    denied: Vec<PathBuf>,
+}
+
+#[pymethods]
+impl PyPatchReport {
+    fn __repr__(&self) -> String {
+        format!("PatchReport(patched={}, skipped={}, errors={})", self.patched.len(), self.skipped.len(), self.errors.len())
+    }
+}
+
+impl From<crate::PatchReport> for PyPatchReport {
+    fn from(report: crate::PatchReport) -> Self {
+        Self {
+            patched: report.patched.into_iter().map(|patched| PyPatchedFile {
+                path: patched.path,
+                old_shebang: patched.old_shebang,
+                new_shebang: patched.new_shebang,
+                tokens_replaced: patched.tokens_replaced,
+            }).collect(),
+            skipped: report.skipped.into_iter().map(|skipped| PySkippedFile {
+                path: skipped.path,
+                code: skipped.code.as_str(),
+                reason: skipped.reason,
+            }).collect(),
+            errors: report.errors.iter().map(ToString::to_string).collect(),
+            unstable: report.unstable,
+            denied: report.denied,
+        }
+    }
+}
+
+/// Runs `patch_tree` without holding the GIL. `path` defaults to $PATH, and `aliases` maps names
+/// found in shebangs to the program to look up instead (or an absolute path to use)
+fn run(py: Python<'_>, root: PathBuf, path: Option<String>, update: bool, jobs: usize, aliases: HashMap<String, String>) -> PyResult<PyPatchReport> {
+    let path_env = path.unwrap_or_else(|| env::var("PATH").unwrap_or_default());
+    let report = py.detach(move || {
+        let options = Options { update, jobs, ..Options::default() };
+        crate::patch_tree(root, &options, &AliasResolver::new(aliases, PathResolver::new(path_env)))
+    });
+    report.map(PyPatchReport::from).map_err(|error| PatchError::new_err(error.to_string()))
+}
+
+/// Patches every executable script under `root`
+#[pyfunction]
+#[pyo3(signature = (root, *, path = None, update = false, jobs = 1, aliases = HashMap::new()))]
+fn patch_tree(py: Python<'_>, root: PathBuf, path: Option<String>, update: bool, jobs: usize, aliases: HashMap<String, String>) -> PyResult<PyPatchReport> {
+    run(py, root, path, update, jobs, aliases)
+}
+
+/// Patches the single script at `file`. Returns what changed, None when it was left alone, and
+/// raises `PatchError` when it couldn't be patched
+#[pyfunction]
+#[pyo3(signature = (file, *, path = None, update = false, aliases = HashMap::new()))]
+fn patch_file(py: Python<'_>, file: PathBuf, path: Option<String>, update: bool, aliases: HashMap<String, String>) -> PyResult<Option<PyPatchedFile>> {
+    if !file.is_file() {
+        return Err(PatchError::new_err(format!("{} is not a file", file.display())));
+    }
+    let report = run(py, file, path, update, 1, aliases)?;
+    if let Some(error) = report.errors.first() {
+        return Err(PatchError::new_err(error.clone()));
+    }
+    Ok(report.patched.first().cloned())
+}
+
+#[pymodule]
+fn patch_shebangs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(patch_tree, module)?)?;
+    module.add_function(wrap_pyfunction!(patch_file, module)?)?;
+    module.add_class::<PyPatchReport>()?;
+    module.add_class::<PyPatchedFile>()?;
+    module.add_class::<PySkippedFile>()?;
+    module.add("PatchError", module.py().get_type::<PatchError>())?;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Interpreter lookups recorded to a file and replayed from it, so a CI re-run rewrites every
+//! shebang exactly as before even if the contents of PATH drifted in between
+use std::{collections::BTreeMap, fs, path::Path, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use crate::{PatchError, Resolver, Result};
+
+/// Program → resolved path, for every lookup that succeeded
+#[derive(Debug, Default)]
+pub struct Resolutions {
+    decisions: Mutex<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResolutionsFile {
+    tool: String,
+    resolutions: BTreeMap<String, String>,
+}
+
+impl Resolutions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, program: &str) -> Option<String> {
+        self.decisions.lock().unwrap().get(program).cloned()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let file: ResolutionsFile = serde_json::from_str(&text).map_err(std::io::Error::from)?;
+        Ok(Self { decisions: Mutex::new(file.resolutions) })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = ResolutionsFile {
+            tool: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string(),
+            resolutions: self.decisions.lock().unwrap().clone(),
+        };
+        let text = serde_json::to_string_pretty(&file).map_err(std::io::Error::from)?;
+        fs::write(path, text + "\n")?;
+        Ok(())
+    }
+}
+
+/// Passes lookups on to `inner`, adding what it finds to `resolutions`
+pub struct RecordingResolver<'a, R> {
+    pub resolutions: &'a Resolutions,
+    inner: R,
+}
+
+impl<'a, R: Resolver> RecordingResolver<'a, R> {
+    pub fn new(resolutions: &'a Resolutions, inner: R) -> Self {
+        Self { resolutions, inner }
+    }
+}
+
+impl<R: Resolver> Resolver for RecordingResolver<'_, R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let path = self.inner.resolve(program)?;
+        self.resolutions.decisions.lock().unwrap().insert(program.to_string(), path.clone());
+        Ok(path)
+    }
+}
+
+/// Answers lookups from `resolutions` only; a program that wasn't recorded is an error
+pub struct ReplayResolver<'a> {
+    pub resolutions: &'a Resolutions,
+    /// Where the resolutions came from, for the error message
+    source: String,
+}
+
+impl<'a> ReplayResolver<'a> {
+    pub fn new(resolutions: &'a Resolutions, source: impl Into<String>) -> Self {
+        Self { resolutions, source: source.into() }
+    }
+}
+
+impl Resolver for ReplayResolver<'_> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        self.resolutions.get(program).ok_or_else(|| PatchError::Resolver {
+            command: format!("--replay {}", self.source),
+            program: program.to_string(),
+            message: "has no recorded path".to_string(),
+        })
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+use crate::PatchError;
+
+/// What `patch_tree` did, file by file
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    pub patched: Vec<PatchedFile>,
+    pub skipped: Vec<SkippedFile>,
+    /// Files that could not be patched; the run continued past them
+    pub errors: Vec<PatchError>,
+    /// Files whose rewrite would change again on a second run (only checked with `verify_idempotent`)
+    pub unstable: Vec<PathBuf>,
+    /// Files and directories skipped because they could not be read or replaced (without `Options::strict`)
+    pub denied: Vec<PathBuf>,
+    /// Files that would have been patched like this, but are on a read-only filesystem
+    /// (with `Options::continue_read_only`)
+    pub read_only: Vec<PatchedFile>,
+    /// The run was stopped through `Options::cancel` before the whole tree was walked
+    pub interrupted: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PatchedFile {
+    pub path: PathBuf,
+    pub old_shebang: String,
+    /// The same as `old_shebang` when only tokens were replaced
+    pub new_shebang: String,
+    /// Occurrences of `Options::tokens` replaced anywhere in the file
+    pub tokens_replaced: usize,
+    /// With `Options::wrap_instead`, where the untouched original now lives
+    /// (`path` is then a wrapper, and `new_shebang` the line that wrapper effectively runs)
+    pub wrapped: Option<PathBuf>,
+    /// Only computed with `Options::hash_contents`
+    pub hashes: Option<ContentHashes>,
+}
+
+/// Hex-encoded sha256 of the whole file before and after patching
+#[derive(Debug, Clone)]
+pub struct ContentHashes {
+    pub before: String,
+    pub after: String,
+    /// Everything after the shebang line (and the nix-shell lines after it, which `Options::nix_shell`
+    /// may rewrite), which patching never changes unless `Options::tokens` replaced something there,
+    /// so downstream tools can verify that only the shebang region was touched
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub code: SkipCode,
+    /// Human-readable, e.g. what is wrong with a malformed shebang
+    pub reason: String,
+}
+
+/// Why a file was left alone, for automation telling harmless skips from suspicious ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipCode {
+    /// A text file without a shebang
+    NoShebang,
+    /// Starts like a compiled binary (a NUL byte among the first bytes), not a script
+    Binary,
+    /// Points into /nix/store already (re-patched with `Options::update` only)
+    StorePath,
+    /// Patching would write the same shebang again
+    UpToDate,
+    /// The `.name-wrapped` original of an `Options::wrap_instead` wrapper
+    WrappedOriginal,
+    /// The shebang can't be patched as written, e.g. `#!` alone or env without a program
+    Malformed,
+    /// A regular file without the owner's executable bit, which the walk doesn't open
+    NotExecutable,
+    /// Rejected by `Options::filter`
+    Excluded,
+    /// Larger than `Options::max_size`
+    TooLarge,
+}
+
+impl SkipCode {
+    /// Stable kebab-case name, e.g. `no-shebang`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoShebang => "no-shebang",
+            Self::Binary => "binary",
+            Self::StorePath => "store-path",
+            Self::UpToDate => "up-to-date",
+            Self::WrappedOriginal => "wrapped-original",
+            Self::Malformed => "malformed",
+            Self::NotExecutable => "not-executable",
+            Self::Excluded => "excluded",
+            Self::TooLarge => "too-large",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::NoShebang => "no shebang",
+            Self::Binary => "binary file",
+            Self::StorePath => "already points into /nix/store",
+            Self::UpToDate => "already up to date",
+            Self::WrappedOriginal => "wrapped original",
+            Self::Malformed => "malformed shebang",
+            Self::NotExecutable => "not executable",
+            Self::Excluded => "excluded by a filter",
+            Self::TooLarge => "over the size limit",
+        }
+    }
+}
+
+#[cfg(feature = "walk")]
+impl SkippedFile {
+    pub(crate) fn new(path: &std::path::Path, code: SkipCode) -> Self {
+        Self { path: path.to_path_buf(), code, reason: code.description().to_string() }
+    }
+}
+
+impl PatchReport {
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty() && self.unstable.is_empty()
+    }
+}
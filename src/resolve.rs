@@ -0,0 +1,443 @@
+//! Interpreter resolution: turning the program named in a shebang into an absolute path
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command as SysCommand, Stdio},
+    sync::{LazyLock, Mutex},
+};
+use crate::{PatchError, Result};
+
+/// Decides which absolute path a shebang should point at for a given program name
+/// (e.g. "bash", or "env" for `env -S` style shebangs).
+/// Shared between the worker threads when `Options::jobs` is above 1.
+pub trait Resolver: Sync {
+    fn resolve(&self, program: &str) -> Result<String>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Box<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        (**self).resolve(program)
+    }
+}
+
+/// Which candidate wins when an interpreter exists in several PATH entries
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ResolveStrategy {
+    #[default]
+    First,
+    Last,
+    Newest,
+    ShortestStorePath,
+}
+
+/// What to write when the interpreter found on PATH is a symlink
+#[derive(Clone, Copy, Default)]
+pub enum SymlinkPolicy {
+    /// Write the path as found (the default), so e.g. /run/current-system/sw/bin/bash keeps following profile updates
+    #[default]
+    Keep,
+    /// Write the fully resolved target, for when the located path is itself ephemeral
+    Canonicalize,
+}
+
+/// A constraint like `python3>=3.10`, checked against the candidate's `--version` output
+#[derive(Clone)]
+pub struct VersionRequirement {
+    spec: String,
+    program: String,
+    op: String,
+    version: Vec<u64>,
+}
+
+impl VersionRequirement {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let op_start = text.find(['<', '>', '=']).ok_or_else(|| format!("expected NAME<op>VERSION, got {:?}", text))?;
+        let (program, rest) = text.split_at(op_start);
+        let op_len = rest.find(|c: char| !"<>=".contains(c)).unwrap_or(rest.len());
+        let (op, version) = rest.split_at(op_len);
+        if !matches!(op, ">=" | "<=" | ">" | "<" | "=" | "==") {
+            return Err(format!("unknown comparison {:?} in {:?}", op, text));
+        }
+        let version = parse_version(version).ok_or_else(|| format!("invalid version in {:?}", text))?;
+        if program.is_empty() {
+            return Err(format!("missing program name in {:?}", text));
+        }
+        Ok(Self { spec: text.to_string(), program: program.to_string(), op: op.to_string(), version })
+    }
+
+    fn is_satisfied_by(&self, version: &[u64]) -> bool {
+        let ordering = compare_versions(version, &self.version);
+        match self.op.as_str() {
+            ">=" => ordering != Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            ">" => ordering == Ordering::Greater,
+            "<" => ordering == Ordering::Less,
+            _ => ordering == Ordering::Equal,
+        }
+    }
+}
+
+fn parse_version(text: &str) -> Option<Vec<u64>> {
+    // compiled once, not for every candidate a lookup checks
+    static VERSION: LazyLock<regex::Regex> = LazyLock::new(|| regex::Regex::new(r"\d+(\.\d+)*").unwrap());
+    let version = VERSION.find(text)?;
+    version.as_str().split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Compares component-wise, treating missing trailing components as 0 (3.10 == 3.10.0)
+fn compare_versions(a: &[u64], b: &[u64]) -> Ordering {
+    let len = a.len().max(b.len());
+    let pad = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..len).map(|i| pad(a, i).cmp(&pad(b, i))).find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+}
+
+pub type TraceFn = Box<dyn Fn(&str) + Send + Sync>;
+
+/// The default resolver: searches a PATH-style list of directories, like `which`
+#[derive(Default)]
+pub struct PathResolver {
+    pub path_env: String,
+    pub strategy: ResolveStrategy,
+    pub requirements: Vec<VersionRequirement>,
+    pub symlinks: SymlinkPolicy,
+    /// Ask nix-locate which packages provide a missing interpreter
+    pub suggest_packages: bool,
+    /// Gets a line for every candidate probed (and why it was rejected), and for the one chosen
+    pub trace: Option<TraceFn>,
+    // `--version` output is only parsed once per candidate
+    version_cache: Mutex<HashMap<PathBuf, Option<Vec<u64>>>>,
+}
+
+impl PathResolver {
+    pub fn new(path_env: impl Into<String>) -> Self {
+        Self { path_env: path_env.into(), ..Self::default() }
+    }
+
+    fn interpreter_version(&self, path: &Path) -> Option<Vec<u64>> {
+        self.version_cache.lock().unwrap().entry(path.to_path_buf()).or_insert_with(|| {
+            let output = SysCommand::new(path).arg("--version").output().ok()?;
+            // python2 and friends print their version on stderr
+            parse_version(&String::from_utf8_lossy(&output.stdout))
+                .or_else(|| parse_version(&String::from_utf8_lossy(&output.stderr)))
+        }).clone()
+    }
+
+    fn trace(&self, message: impl FnOnce() -> String) {
+        if let Some(trace) = &self.trace {
+            trace(&message());
+        }
+    }
+}
+
+/// The directories of a search path
+#[cfg(not(target_os = "wasi"))]
+fn search_dirs(path_env: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    std::env::split_paths(path_env)
+}
+
+// std has no PATH separator for WASI (split_paths panics), so it is taken to be the Unix one
+#[cfg(target_os = "wasi")]
+fn search_dirs(path_env: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    path_env.split(':').map(PathBuf::from)
+}
+
+impl Resolver for PathResolver {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let requirements: Vec<_> = self.requirements.iter().filter(|r| r.program == program).collect();
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        self.trace(|| format!("looking up {} in PATH={}", program, self.path_env));
+        for full_path in search_dirs(&self.path_env).flat_map(|dir| program_candidates(&dir, program)) {
+            match candidate_problem(&full_path) {
+                None => {
+                    if !requirements.is_empty() {
+                        let Some(version) = self.interpreter_version(&full_path) else {
+                            skipped.push(format!("{} (no version in --version output)", full_path.display()));
+                            self.trace(|| format!("    {}: rejected, no version in --version output", full_path.display()));
+                            continue;
+                        };
+                        if let Some(failed) = requirements.iter().find(|r| !r.is_satisfied_by(&version)) {
+                            let version = version.iter().map(u64::to_string).collect::<Vec<_>>().join(".");
+                            skipped.push(format!("{} (version {} does not satisfy {})", full_path.display(), version, failed.spec));
+                            self.trace(|| format!("    {}: rejected, version {} does not satisfy {}", full_path.display(), version, failed.spec));
+                            continue;
+                        }
+                    }
+                    self.trace(|| format!("    {}: usable", full_path.display()));
+                    found.push(full_path);
+                    if matches!(self.strategy, ResolveStrategy::First) {
+                        break;
+                    }
+                }
+                Some(Candidate::Missing) => self.trace(|| format!("    {}: not there", full_path.display())),
+                Some(Candidate::Unsuitable(reason)) => {
+                    self.trace(|| format!("    {}: rejected, {}", full_path.display(), reason));
+                    skipped.push(format!("{} ({})", full_path.display(), reason));
+                }
+            }
+        }
+
+        // ties always keep the earliest PATH entry
+        let chosen = match self.strategy {
+            ResolveStrategy::First => found.into_iter().next(),
+            ResolveStrategy::Last => found.into_iter().last(),
+            ResolveStrategy::Newest => found.into_iter().rev().max_by_key(|path| {
+                fs::metadata(path).and_then(|m| m.modified()).ok()
+            }),
+            // store paths beat everything else, then the shortest one wins
+            ResolveStrategy::ShortestStorePath => found.into_iter().min_by_key(|path| {
+                (!path.starts_with("/nix/store"), path.as_os_str().len())
+            }),
+        };
+        if let Some(path) = chosen {
+            let path = match self.symlinks {
+                SymlinkPolicy::Keep => path,
+                SymlinkPolicy::Canonicalize => fs::canonicalize(&path)?,
+            };
+            self.trace(|| format!("    chose {}", path.display()));
+            return Ok(path.to_string_lossy().to_string());
+        }
+
+        Err(PatchError::MissingInterpreter {
+            program: program.to_string(),
+            searched: self.path_env.clone(),
+            skipped,
+            hint: self.suggest_packages.then(|| package_hint(program)),
+        })
+    }
+}
+
+/// Asks an external shell command first, falling back to another resolver when it prints nothing.
+/// The command gets the program name as its argument and on stdin.
+pub struct CommandResolver<R> {
+    command: String,
+    fallback: R,
+    cache: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl<R: Resolver> CommandResolver<R> {
+    pub fn new(command: impl Into<String>, fallback: R) -> Self {
+        Self { command: command.into(), fallback, cache: Mutex::new(HashMap::new()) }
+    }
+
+    fn run(&self, program: &str) -> Result<Option<String>> {
+        if let Some(cached) = self.cache.lock().unwrap().get(program) {
+            return Ok(cached.clone());
+        }
+
+        let mut child = SysCommand::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg("sh")
+            .arg(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // the resolver may not read stdin at all, so a broken pipe is fine here
+        let _ = writeln!(child.stdin.take().unwrap(), "{}", program);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let message = format!("failed ({})", output.status);
+            return Err(PatchError::Resolver { command: self.command.clone(), program: program.to_string(), message });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let resolved = stdout.trim();
+        let resolved = if resolved.is_empty() {
+            None
+        } else if !Path::new(resolved).is_absolute() {
+            let message = format!("returned a relative path ({})", resolved);
+            return Err(PatchError::Resolver { command: self.command.clone(), program: program.to_string(), message });
+        } else {
+            Some(resolved.to_string())
+        };
+        self.cache.lock().unwrap().insert(program.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+}
+
+impl<R: Resolver> Resolver for CommandResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        match self.run(program)? {
+            Some(path) => Ok(path),
+            None => self.fallback.resolve(program),
+        }
+    }
+}
+
+/// Uses fixed interpreter paths for some programs, falling back to another resolver for the rest
+pub struct MappingResolver<R> {
+    pub map: HashMap<String, String>,
+    fallback: R,
+}
+
+impl<R: Resolver> MappingResolver<R> {
+    pub fn new(map: HashMap<String, String>, fallback: R) -> Self {
+        Self { map, fallback }
+    }
+}
+
+impl<R: Resolver> Resolver for MappingResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        match self.map.get(program) {
+            Some(path) => Ok(path.clone()),
+            None => self.fallback.resolve(program),
+        }
+    }
+}
+
+/// Looks some names up as other names, e.g. the `@bash@` placeholders of script templates as bash.
+/// A target that is an absolute path is used as is.
+pub struct AliasResolver<R> {
+    pub aliases: HashMap<String, String>,
+    fallback: R,
+}
+
+impl<R: Resolver> AliasResolver<R> {
+    pub fn new(aliases: HashMap<String, String>, fallback: R) -> Self {
+        Self { aliases, fallback }
+    }
+}
+
+impl<R: Resolver> Resolver for AliasResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        match self.aliases.get(program) {
+            Some(target) if Path::new(target).is_absolute() => Ok(target.clone()),
+            Some(target) => self.fallback.resolve(target),
+            None => self.fallback.resolve(program),
+        }
+    }
+}
+
+/// Remembers what `inner` found for each program, so repeated lookups (many small runs against the
+/// same PATH, as in the daemon) skip the search. Failures aren't cached: the program may get installed
+pub struct CachingResolver<R> {
+    found: Mutex<HashMap<String, String>>,
+    inner: R,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self { found: Mutex::default(), inner }
+    }
+
+    /// Drops every remembered lookup, e.g. after PATH's contents changed
+    pub fn clear(&self) {
+        self.found.lock().unwrap().clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        if let Some(path) = self.found.lock().unwrap().get(program) {
+            return Ok(path.clone());
+        }
+        let path = self.inner.resolve(program)?;
+        self.found.lock().unwrap().insert(program.to_string(), path.clone());
+        Ok(path)
+    }
+}
+
+/// Points every program at its standard FHS location instead of looking for it: the classic shells
+/// in /bin, everything else (env included) in /usr/bin. Nothing is checked to exist, as the tree is
+/// meant for another system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FhsResolver;
+
+impl FhsResolver {
+    // the ones distros keep (or link) in /bin, and that scripts expect there
+    const BIN: [&str; 6] = ["sh", "bash", "dash", "ksh", "csh", "tcsh"];
+}
+
+impl Resolver for FhsResolver {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let dir = if Self::BIN.contains(&program) { "/bin" } else { "/usr/bin" };
+        Ok(format!("{}/{}", dir, program))
+    }
+}
+
+/// Whether `busybox --list` includes `applet`, listing each busybox binary only once
+pub(crate) fn busybox_has_applet(busybox: &Path, applet: &str) -> bool {
+    static APPLETS: LazyLock<Mutex<HashMap<PathBuf, Vec<String>>>> = LazyLock::new(Default::default);
+    let mut applets = APPLETS.lock().unwrap();
+    let listed = applets.entry(busybox.to_path_buf()).or_insert_with(|| {
+        let output = SysCommand::new(busybox).arg("--list").output();
+        output.map(|output| String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect()).unwrap_or_default()
+    });
+    listed.iter().any(|listed| listed == applet)
+}
+
+/// Asks the nix-index database which packages ship `bin/<program>`
+fn package_hint(program: &str) -> String {
+    let output = SysCommand::new("nix-locate")
+        .args(["--minimal", "--top-level", "--whole-name", "--at-root"])
+        .arg(format!("/bin/{}", program))
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let packages: Vec<_> = String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect();
+            if packages.is_empty() {
+                format!("no package in the nix-index database provides bin/{}", program)
+            } else {
+                format!("bin/{} is provided by: {}", program, packages.join(", "))
+            }
+        }
+        Ok(output) => format!("nix-locate failed: {}", String::from_utf8_lossy(&output.stderr).trim()),
+        Err(_) => "install nix-index (nix-locate) to get package suggestions".to_string(),
+    }
+}
+
+/// The paths to try for `program` in one PATH entry
+#[cfg(not(windows))]
+fn program_candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    vec![dir.join(program)]
+}
+
+/// Like `where`: the bare name first (Git Bash and MSYS2 ship extensionless scripts too),
+/// then the name with each PATHEXT extension, unless it already has one
+#[cfg(windows)]
+fn program_candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    let mut candidates = vec![dir.join(program)];
+    if Path::new(program).extension().is_none() {
+        let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+        candidates.extend(pathext.split(';').filter(|ext| !ext.is_empty()).map(|ext| dir.join(format!("{}{}", program, ext.to_lowercase()))));
+    }
+    candidates
+}
+
+enum Candidate {
+    Missing,
+    Unsuitable(&'static str),
+}
+
+/// Returns why a PATH candidate can't be used as an interpreter, or None if it can
+fn candidate_problem(path: &Path) -> Option<Candidate> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        // fs::metadata follows symlinks, so a dangling one looks missing here
+        Err(_) if fs::symlink_metadata(path).is_ok() => return Some(Candidate::Unsuitable("broken symlink")),
+        Err(_) => return Some(Candidate::Missing),
+    };
+    if metadata.is_dir() {
+        return Some(Candidate::Unsuitable("directory"));
+    }
+    if !metadata.is_file() {
+        return Some(Candidate::Unsuitable("not a regular file"));
+    }
+    // like `which`, only accept files that can actually be executed
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Some(Candidate::Unsuitable("not executable"));
+        }
+    }
+    if File::open(path).is_err() {
+        return Some(Candidate::Unsuitable("unreadable"));
+    }
+    None
+}
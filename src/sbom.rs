@@ -0,0 +1,105 @@
+//! CycloneDX SBOM listing the interpreters that patched scripts now depend on
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, time::SystemTime};
+use serde_json::{Value, json};
+use crate::{PatchedFile, Result, closure::store_path_of, shebang::{self, ShebangStyle}};
+
+/// Collects interpreter → referencing files across one or more runs
+#[derive(Debug, Default)]
+pub struct Sbom {
+    // keyed by store path (or the interpreter itself outside the store) so output is sorted and deduplicated
+    components: BTreeMap<String, Component>,
+}
+
+#[derive(Debug)]
+struct Component {
+    interpreters: Vec<String>,
+    files: Vec<PathBuf>,
+}
+
+impl Sbom {
+    pub fn record(&mut self, patched: &PatchedFile) {
+        for interpreter in interpreters_in(&patched.new_shebang) {
+            let key = store_path_of(&interpreter).unwrap_or(&interpreter).to_string();
+            let component = self.components.entry(key).or_insert_with(|| Component { interpreters: Vec::new(), files: Vec::new() });
+            if !component.interpreters.contains(&interpreter) {
+                component.interpreters.push(interpreter.clone());
+            }
+            if !component.files.contains(&patched.path) {
+                component.files.push(patched.path.clone());
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let components: Vec<Value> = self.components.iter().map(|(key, component)| {
+            let (name, version) = match store_path_of(key) {
+                Some(store_path) => split_store_name(store_path),
+                None => (Path::new(key).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| key.clone()), None),
+            };
+            let mut properties = vec![];
+            if store_path_of(key).is_some() {
+                properties.push(json!({ "name": "nix:store_path", "value": key }));
+            }
+            for interpreter in &component.interpreters {
+                properties.push(json!({ "name": "interpreter", "value": interpreter }));
+            }
+            let mut value = json!({
+                "type": "application",
+                "bom-ref": key,
+                "name": name,
+                "properties": properties,
+                "evidence": {
+                    "occurrences": component.files.iter().map(|f| json!({ "location": f.to_string_lossy() })).collect::<Vec<_>>(),
+                },
+            });
+            if let Some(version) = version {
+                value["version"] = json!(version);
+            }
+            value
+        }).collect();
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "version": 1,
+            "metadata": {
+                "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                "tools": {
+                    "components": [{ "type": "application", "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") }],
+                },
+            },
+            "components": components,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(&self.to_json()).map_err(std::io::Error::from)?;
+        fs::write(path, text + "\n")?;
+        Ok(())
+    }
+}
+
+/// The interpreter paths a shebang runs: the interpreter itself, plus the program for env-style shebangs
+pub(crate) fn interpreters_in(shebang: &str) -> Vec<String> {
+    let Some(parsed) = shebang::parse(shebang) else {
+        return Vec::new();
+    };
+    let mut interpreters = vec![parsed.interpreter.text.to_string()];
+    if parsed.style != ShebangStyle::Direct {
+        interpreters.extend(parsed.program().map(|program| program.text.to_string()));
+    }
+    interpreters
+}
+
+/// "/nix/store/<hash>-bash-interactive-5.2p37" -> ("bash-interactive", Some("5.2p37")), like nix's parseDrvName
+fn split_store_name(store_path: &str) -> (String, Option<String>) {
+    let base = store_path.trim_start_matches("/nix/store/");
+    let name = base.split_once('-').map(|(_, name)| name).unwrap_or(base);
+    let version_start = name.char_indices().find(|&(i, c)| {
+        c == '-' && name[i + 1..].starts_with(|c: char| c.is_ascii_digit())
+    });
+    match version_start {
+        Some((i, _)) => (name[..i].to_string(), Some(name[i + 1..].to_string())),
+        None => (name.to_string(), None),
+    }
+}
@@ -0,0 +1,127 @@
+//! The shebang parser used for every rewrite, exposed so linters and analyzers can
+//! see a line exactly the way this crate does
+use std::ops::Range;
+
+/// A whitespace-separated word of the line, with its byte range in the line that was parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub span: Range<usize>,
+}
+
+/// Which env implementation's option syntax a shebang is read with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EnvDialect {
+    /// GNU coreutils: `-S` has to come first, on its own
+    #[default]
+    Gnu,
+    /// FreeBSD/NetBSD: `-i`/`-v` may be clustered with `-S` (`-iS`), and `-P altpath` names where
+    /// the program is looked up instead of PATH
+    Bsd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShebangStyle {
+    /// `#!/bin/bash -e`: the interpreter is run directly
+    Direct,
+    /// `#!/usr/bin/env bash`: env looks the program (the first arg) up on PATH
+    Env,
+    /// `#!/usr/bin/env -S bash -e`: env options ending in `-S`, then the program
+    EnvSplit,
+    /// env with other options or `NAME=value` assignments, which are not rewritten
+    EnvComplex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shebang<'a> {
+    pub interpreter: Token<'a>,
+    pub args: Vec<Token<'a>>,
+    pub style: ShebangStyle,
+    /// For `Env` and `EnvSplit`, the index in `args` of the program env runs; everything before it
+    /// are env options
+    pub program_arg: Option<usize>,
+}
+
+impl Shebang<'_> {
+    /// The program that ends up running the script: the interpreter itself, or what env is asked to find.
+    /// `None` for env without a program, and for `EnvComplex`
+    pub fn program(&self) -> Option<&Token<'_>> {
+        match self.style {
+            ShebangStyle::Direct => Some(&self.interpreter),
+            ShebangStyle::Env | ShebangStyle::EnvSplit => self.args.get(self.program_arg?),
+            ShebangStyle::EnvComplex => None,
+        }
+    }
+}
+
+/// Parses a shebang line (a trailing line break is fine) the way GNU env reads it. Returns `None`
+/// when the line doesn't start with `#!` or names no interpreter at all
+pub fn parse(line: &str) -> Option<Shebang<'_>> {
+    parse_with(line, EnvDialect::Gnu)
+}
+
+/// Like `parse`, with env options read in the given dialect
+pub fn parse_with(line: &str, dialect: EnvDialect) -> Option<Shebang<'_>> {
+    let content = line.strip_prefix("#!")?;
+    let mut tokens = tokens(content, 2);
+    let interpreter = tokens.next()?;
+    let args: Vec<_> = tokens.collect();
+    let (style, program_arg) = if !interpreter.text.ends_with("/env") {
+        (ShebangStyle::Direct, None)
+    } else {
+        match dialect {
+            EnvDialect::Gnu => gnu_env_style(&args),
+            EnvDialect::Bsd => bsd_env_style(&args),
+        }
+    };
+    Some(Shebang { interpreter, args, style, program_arg })
+}
+
+fn gnu_env_style(args: &[Token<'_>]) -> (ShebangStyle, Option<usize>) {
+    match args.first().map(|arg| arg.text) {
+        Some("-S") => (ShebangStyle::EnvSplit, Some(1)),
+        Some(arg) if arg.starts_with('-') || arg.contains('=') => (ShebangStyle::EnvComplex, None),
+        _ => (ShebangStyle::Env, Some(0)),
+    }
+}
+
+fn bsd_env_style(args: &[Token<'_>]) -> (ShebangStyle, Option<usize>) {
+    let mut index = 0;
+    let mut other_options = false;
+    while let Some(arg) = args.get(index).map(|arg| arg.text) {
+        if arg.starts_with("-P") {
+            // `-P altpath` or `-Paltpath`
+            index += if arg == "-P" { 2 } else { 1 };
+            continue;
+        }
+        if let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) {
+            let (clustered, split) = match flags.strip_suffix('S') {
+                Some(clustered) => (clustered, true),
+                None => (flags, false),
+            };
+            if !clustered.chars().all(|flag| matches!(flag, 'i' | 'v')) {
+                return (ShebangStyle::EnvComplex, None);
+            }
+            if split {
+                return (ShebangStyle::EnvSplit, Some(index + 1));
+            }
+            other_options |= clustered.contains('i');
+        } else if arg.contains('=') || other_options {
+            // an emptied environment or assignments can't be expressed by running the program directly
+            return (ShebangStyle::EnvComplex, None);
+        } else {
+            return (ShebangStyle::Env, Some(index));
+        }
+        index += 1;
+    }
+    (ShebangStyle::Env, Some(index))
+}
+
+fn tokens(text: &str, offset: usize) -> impl Iterator<Item = Token<'_>> {
+    text.split_whitespace().map(move |word| {
+        // split_whitespace yields subslices, so the pointer difference is the position
+        let start = offset + (word.as_ptr() as usize - text.as_ptr() as usize);
+        Token { text: word, span: start..start + word.len() }
+    })
+}
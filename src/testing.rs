@@ -0,0 +1,168 @@
+//! Throwaway script trees and assertions on what patching did with them, for integration tests of
+//! crates embedding this one. Everything here panics (with the path involved) instead of returning
+//! errors, as a test would do with them anyway
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use crate::{PatchError, PatchReport, SkipCode};
+
+/// A directory under the system temp dir, removed again when dropped.
+/// Names passed to its methods are relative to it, with `/` creating subdirectories as needed
+#[derive(Debug)]
+pub struct ScriptTree {
+    root: PathBuf,
+}
+
+impl ScriptTree {
+    pub fn new() -> Self {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let name = format!("patch-shebangs-test-{}-{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::Relaxed), nanos);
+        let root = std::env::temp_dir().join(name);
+        fs::create_dir_all(&root).unwrap_or_else(|error| panic!("could not create {}: {}", root.display(), error));
+        Self { root }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// An executable (0755) file with `contents`, shebang included
+    pub fn script(&self, name: &str, contents: &str) -> &Self {
+        self.file(name, contents, 0o755)
+    }
+
+    pub fn file(&self, name: &str, contents: &str, mode: u32) -> &Self {
+        let path = self.prepare(name);
+        fs::write(&path, contents).unwrap_or_else(|error| panic!("could not write {}: {}", path.display(), error));
+        set_mode(&path, mode);
+        self
+    }
+
+    pub fn dir(&self, name: &str) -> &Self {
+        let path = self.root.join(name);
+        fs::create_dir_all(&path).unwrap_or_else(|error| panic!("could not create {}: {}", path.display(), error));
+        self
+    }
+
+    /// `name` pointing at `target`, which is taken as is (relative to the link's directory when relative)
+    pub fn symlink(&self, name: &str, target: &str) -> &Self {
+        let path = self.prepare(name);
+        symlink(Path::new(target), &path);
+        self
+    }
+
+    /// A stand-in interpreter in `<tree>/interpreters`, which `path_env` puts on the search path.
+    /// Returns its absolute path, which is what patched shebangs should point at
+    pub fn interpreter(&self, program: &str) -> String {
+        let name = format!("interpreters/{}", program);
+        self.script(&name, "#!/bin/sh\nexit 0\n");
+        self.root.join(name).to_string_lossy().to_string()
+    }
+
+    /// A search path with just the `interpreter`s, for `PathResolver::new`
+    pub fn path_env(&self) -> String {
+        self.root.join("interpreters").to_string_lossy().to_string()
+    }
+
+    pub fn first_line(&self, name: &str) -> String {
+        let path = self.root.join(name);
+        let contents = fs::read(&path).unwrap_or_else(|error| panic!("could not read {}: {}", path.display(), error));
+        let line = contents.split(|&b| b == b'\n').next().unwrap_or_default();
+        String::from_utf8_lossy(line).to_string()
+    }
+
+    pub fn contents(&self, name: &str) -> String {
+        let path = self.root.join(name);
+        fs::read_to_string(&path).unwrap_or_else(|error| panic!("could not read {}: {}", path.display(), error))
+    }
+
+    /// The permission bits, which patching has to keep
+    #[cfg(unix)]
+    pub fn mode(&self, name: &str) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        let path = self.root.join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap_or_else(|error| panic!("could not stat {}: {}", path.display(), error));
+        metadata.permissions().mode() & 0o7777
+    }
+
+    fn prepare(&self, name: &str) -> PathBuf {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap_or_else(|error| panic!("could not create {}: {}", parent.display(), error));
+        }
+        path
+    }
+}
+
+impl Default for ScriptTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ScriptTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Asserts that `report` has the file at `path` patched to `shebang` (the whole line, `#!` included)
+pub fn assert_patched(report: &PatchReport, path: impl AsRef<Path>, shebang: &str) {
+    let path = path.as_ref();
+    match report.patched.iter().find(|patched| patched.path == path) {
+        Some(patched) => assert_eq!(patched.new_shebang, shebang, "{} was patched to another shebang", path.display()),
+        None => panic!("{} was not patched; {}", path.display(), fate(report, path)),
+    }
+}
+
+/// Asserts that `report` has the file at `path` skipped for `code`
+pub fn assert_skipped(report: &PatchReport, path: impl AsRef<Path>, code: SkipCode) {
+    let path = path.as_ref();
+    match report.skipped.iter().find(|skipped| skipped.path == path) {
+        Some(skipped) => assert_eq!(skipped.code, code, "{} was skipped for another reason: {}", path.display(), skipped.reason),
+        None => panic!("{} was not skipped; {}", path.display(), fate(report, path)),
+    }
+}
+
+/// Asserts that `report` has an error for the file at `path`, and returns it to look into
+pub fn assert_failed(report: &PatchReport, path: impl AsRef<Path>) -> &PatchError {
+    let path = path.as_ref();
+    let failed = report.errors.iter().find(|error| matches!(error, PatchError::InFile { path: failed, .. } if failed == path));
+    failed.unwrap_or_else(|| panic!("{} did not fail; {}", path.display(), fate(report, path)))
+}
+
+/// What happened to `path` instead, for the panic messages
+fn fate(report: &PatchReport, path: &Path) -> String {
+    if let Some(patched) = report.patched.iter().find(|patched| patched.path == path) {
+        format!("it was patched to {}", patched.new_shebang)
+    } else if let Some(skipped) = report.skipped.iter().find(|skipped| skipped.path == path) {
+        format!("it was skipped [{}]: {}", skipped.code.as_str(), skipped.reason)
+    } else if let Some(error) = report.errors.iter().find(|error| matches!(error, PatchError::InFile { path: failed, .. } if failed == path)) {
+        format!("it failed: {}", error)
+    } else {
+        "the report doesn't mention it (not executable, or filtered out?)".to_string()
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap_or_else(|error| panic!("could not chmod {}: {}", path.display(), error));
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) {}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) {
+    std::os::unix::fs::symlink(target, link).unwrap_or_else(|error| panic!("could not create {}: {}", link.display(), error));
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, link: &Path) {
+    panic!("could not create {}: symlinks are only supported on unix", link.display());
+}
@@ -0,0 +1,37 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Caps how many bytes per second `patch_tree` reads and writes, shared by all workers,
+/// so patching a huge tree in the background leaves disk bandwidth for other jobs
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    // when the budget started, and how much of it has been used since
+    used: Mutex<(Instant, u64)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self { bytes_per_second: bytes_per_second.max(1), used: Mutex::new((Instant::now(), 0)) }
+    }
+
+    /// Accounts for `bytes` of I/O, sleeping until they fit into the rate
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut used = self.used.lock().unwrap();
+            // an idle stretch doesn't turn into a burst later on
+            let elapsed = used.0.elapsed();
+            if elapsed > Duration::from_secs(1) && Duration::from_secs_f64(used.1 as f64 / self.bytes_per_second as f64) < elapsed {
+                *used = (Instant::now(), 0);
+            }
+            used.1 += bytes as u64;
+            Duration::from_secs_f64(used.1 as f64 / self.bytes_per_second as f64).saturating_sub(used.0.elapsed())
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::Duration,
+};
+#[cfg(feature = "walk")]
+use std::{cell::RefCell, thread, time::Instant};
+#[cfg(feature = "walk")]
+use crate::{Options, Resolver, Result};
+
+/// Where `patch_tree` spends its time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Walking the tree and statting its entries
+    Traversal,
+    /// Reading first lines and deciding what to do with each file
+    Classification,
+    /// Looking interpreters (and token programs) up
+    Resolution,
+    /// Writing the patched files, including streaming their bodies through
+    Writing,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Traversal, Phase::Classification, Phase::Resolution, Phase::Writing];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Traversal => "traversal",
+            Phase::Classification => "classification",
+            Phase::Resolution => "resolution",
+            Phase::Writing => "writing",
+        }
+    }
+}
+
+#[cfg(feature = "walk")]
+thread_local! {
+    // the phases this thread is in, innermost last, with when each one last started counting
+    static CURRENT: RefCell<Vec<(Phase, Instant)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Time spent per phase and per thread, collected when set as `Options::timings`.
+/// Nested phases are counted exclusively: resolving during classification counts as resolution only
+#[derive(Debug, Default)]
+pub struct Timings {
+    by_thread: Mutex<BTreeMap<String, [Duration; Phase::ALL.len()]>>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Each thread's time in every one of `Phase::ALL`, by thread name
+    pub fn by_thread(&self) -> BTreeMap<String, [Duration; Phase::ALL.len()]> {
+        self.by_thread.lock().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "walk")]
+impl Timings {
+    fn add(&self, phase: Phase, elapsed: Duration) {
+        let thread = thread::current().name().unwrap_or("unnamed").to_string();
+        self.by_thread.lock().unwrap().entry(thread).or_default()[phase as usize] += elapsed;
+    }
+
+    fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        CURRENT.with_borrow_mut(|current| {
+            if let Some(&(outer, since)) = current.last() {
+                self.add(outer, started - since);
+            }
+            current.push((phase, started));
+        });
+        let result = f();
+        let finished = Instant::now();
+        CURRENT.with_borrow_mut(|current| {
+            if let Some((phase, since)) = current.pop() {
+                self.add(phase, finished - since);
+            }
+            if let Some((_, since)) = current.last_mut() {
+                *since = finished;
+            }
+        });
+        result
+    }
+}
+
+/// Runs `f` as `phase` when `options.timings` is set
+#[cfg(feature = "walk")]
+pub(crate) fn timed<T>(options: &Options, phase: Phase, f: impl FnOnce() -> T) -> T {
+    match &options.timings {
+        Some(timings) => timings.time(phase, f),
+        None => f(),
+    }
+}
+
+/// Counts every lookup as `Phase::Resolution`
+#[cfg(feature = "walk")]
+pub(crate) struct TimedResolver<'a, R: ?Sized> {
+    pub(crate) timings: &'a Timings,
+    pub(crate) inner: &'a R,
+}
+
+#[cfg(feature = "walk")]
+impl<R: Resolver + ?Sized> Resolver for TimedResolver<'_, R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        self.timings.time(Phase::Resolution, || self.inner.resolve(program))
+    }
+}
@@ -0,0 +1,97 @@
+//! The `io-uring` feature: the scan phase's statx, openat and read calls are submitted a batch at a time,
+//! instead of three syscalls per walked file
+use std::{
+    ffi::CString,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+use io_uring::{IoUring, opcode, squeue, types};
+use crate::process::PROBE_LEN;
+
+/// What the scan learned about one file
+pub(crate) struct Probe {
+    /// `st_mode` of the file itself (symlinks are not followed)
+    pub(crate) mode: io::Result<u32>,
+    /// 0 when the statx failed
+    pub(crate) size: u64,
+    /// Up to `PROBE_LEN` leading bytes of owner-executable regular files; `None` when they could not be
+    /// read this way, so the file gets opened normally later
+    pub(crate) head: Option<Vec<u8>>,
+}
+
+pub(crate) struct Scanner {
+    ring: IoUring,
+}
+
+impl Scanner {
+    /// `None` when the kernel refuses io_uring (too old, or disabled by a seccomp policy)
+    pub(crate) fn new() -> Option<Self> {
+        IoUring::new(BATCH as u32).ok().map(|ring| Self { ring })
+    }
+
+    pub(crate) fn probe(&mut self, paths: &[&Path]) -> io::Result<Vec<Probe>> {
+        let names: Vec<CString> = paths.iter().map(|path| CString::new(path.as_os_str().as_bytes())).collect::<Result<_, _>>()?;
+        let mut stats: Vec<libc::statx> = vec![unsafe { std::mem::zeroed() }; paths.len()];
+
+        let statx = names.iter().zip(&mut stats).map(|(name, stat)| {
+            opcode::Statx::new(types::Fd(libc::AT_FDCWD), name.as_ptr(), (stat as *mut libc::statx).cast())
+                .flags(libc::AT_SYMLINK_NOFOLLOW)
+                .mask(libc::STATX_TYPE | libc::STATX_MODE | libc::STATX_SIZE)
+                .build()
+        });
+        let statted = self.run(statx.collect())?;
+        let mut probes: Vec<Probe> = statted.iter().zip(&stats).map(|(&result, stat)| Probe {
+            mode: if result < 0 { Err(io::Error::from_raw_os_error(-result)) } else { Ok(u32::from(stat.stx_mode)) },
+            size: stat.stx_size,
+            head: None,
+        }).collect();
+
+        let candidates: Vec<usize> = (0..paths.len())
+            .filter(|&i| probes[i].mode.as_ref().is_ok_and(|&mode| mode & libc::S_IFMT == libc::S_IFREG && mode & 0o100 != 0))
+            .collect();
+        let open = candidates.iter().map(|&i| {
+            opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), names[i].as_ptr()).flags(libc::O_RDONLY | libc::O_CLOEXEC).build()
+        });
+        let fds = self.run(open.collect())?;
+
+        let opened: Vec<(usize, i32)> = candidates.into_iter().zip(fds).filter(|&(_, fd)| fd >= 0).collect();
+        let mut heads = vec![vec![0; PROBE_LEN]; opened.len()];
+        let read = opened.iter().zip(&mut heads).map(|(&(_, fd), head)| {
+            opcode::Read::new(types::Fd(fd), head.as_mut_ptr(), PROBE_LEN as u32).build()
+        });
+        let lengths = self.run(read.collect())?;
+        let close = opened.iter().map(|&(_, fd)| opcode::Close::new(types::Fd(fd)).build());
+        self.run(close.collect())?;
+
+        for ((&(i, _), mut head), length) in opened.iter().zip(heads).zip(lengths) {
+            // a short read is fine (the file is that small), anything else falls back to a normal read
+            if length >= 0 {
+                head.truncate(length as usize);
+                probes[i].head = Some(head);
+            }
+        }
+        Ok(probes)
+    }
+
+    /// Submits `entries` (at most `BATCH` at a time) and returns their results in the same order
+    fn run(&mut self, entries: Vec<squeue::Entry>) -> io::Result<Vec<i32>> {
+        let mut results = vec![0; entries.len()];
+        for (chunk_index, chunk) in entries.chunks(BATCH).enumerate() {
+            let offset = chunk_index * BATCH;
+            for (i, entry) in chunk.iter().enumerate() {
+                let entry = entry.clone().user_data((offset + i) as u64);
+                // SAFETY: every pointer in the entries outlives this call, which waits for all completions
+                unsafe { self.ring.submission().push(&entry) }.map_err(io::Error::other)?;
+            }
+            self.ring.submit_and_wait(chunk.len())?;
+            for completion in self.ring.completion() {
+                results[completion.user_data() as usize] = completion.result();
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// How many files are probed per batch (and the ring size)
+pub(crate) const BATCH: usize = 64;
@@ -0,0 +1,86 @@
+//! Copies of the resolved interpreters inside the tree itself, which the shebangs then point at, for
+//! trees that are exported and run somewhere the store paths don't exist
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use crate::{Resolver, Result};
+
+/// Passes lookups on to `inner`, and answers with a copy of what it found in `dir` (made on first use)
+pub struct VendoringResolver<R> {
+    /// Absolute, since shebangs point into it
+    pub dir: PathBuf,
+    /// Symlink the interpreters instead of copying them, which keeps the tree small but not self-contained
+    pub symlink: bool,
+    // resolved path → vendored path
+    vendored: Mutex<HashMap<String, String>>,
+    inner: R,
+}
+
+impl<R: Resolver> VendoringResolver<R> {
+    pub fn new(dir: impl Into<PathBuf>, inner: R) -> Self {
+        Self { dir: dir.into(), symlink: false, vendored: Mutex::default(), inner }
+    }
+
+    fn vendor(&self, interpreter: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let name = interpreter.file_name().unwrap_or_default().to_string_lossy();
+        // two different interpreters of the same name (python3 from two packages) get a suffix each
+        for attempt in 1.. {
+            let target = match attempt {
+                1 => self.dir.join(&*name),
+                _ => self.dir.join(format!("{}-{}", name, attempt)),
+            };
+            if fs::symlink_metadata(&target).is_err() {
+                if self.symlink {
+                    symlink(interpreter, &target)?;
+                } else {
+                    fs::copy(interpreter, &target)?;
+                }
+                return Ok(target);
+            }
+            // left by an earlier run
+            if self.is_vendored_copy(interpreter, &target) {
+                return Ok(target);
+            }
+        }
+        unreachable!()
+    }
+
+    fn is_vendored_copy(&self, interpreter: &Path, target: &Path) -> bool {
+        if self.symlink {
+            fs::read_link(target).is_ok_and(|link| link == interpreter)
+        } else {
+            !target.is_symlink() && matches!((fs::read(interpreter), fs::read(target)), (Ok(a), Ok(b)) if a == b)
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for VendoringResolver<R> {
+    fn resolve(&self, program: &str) -> Result<String> {
+        let path = self.inner.resolve(program)?;
+        if Path::new(&path).starts_with(&self.dir) {
+            return Ok(path);
+        }
+        let mut vendored = self.vendored.lock().unwrap();
+        if let Some(target) = vendored.get(&path) {
+            return Ok(target.clone());
+        }
+        let target = self.vendor(Path::new(&path))?.to_string_lossy().to_string();
+        vendored.insert(path, target.clone());
+        Ok(target)
+    }
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(original, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(_original: &Path, _link: &Path) -> Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks are only supported on unix").into())
+}